@@ -163,6 +163,12 @@ pub mod aom {
             }
         }
 
+        /// How much prediction error the encoder has to spend bits on for this frame: the error left after inter
+        /// prediction, or after intra prediction when that does better.
+        pub fn complexity(&self) -> f64 {
+            self.coded_error.min(self.intra_error)
+        }
+
         pub async fn read_aom_firstpass(
             reader: &mut (impl AsyncRead + Unpin),
         ) -> Result<AomFirstpass, Error> {
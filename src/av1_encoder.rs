@@ -1,13 +1,98 @@
-use crate::encoder::{Encoder, EncoderOptions};
+use crate::color::{ColorInfo, Naming};
+use crate::encoder::{
+    included_encoder_version, Encoder, EncoderOptions, Passes, QualityDirection, QualityParameter,
+};
 use tokio::process::Command;
 
-pub struct Av1Encoder {}
+/// How aomenc should deal with film grain.
+#[derive(Copy, Clone, Debug, PartialEq, Default)]
+pub enum FilmGrain {
+    #[default]
+    Off,
+    /// Let aomenc denoise each scene at this strength and model the removed noise as grain.
+    /// The probes still score against the noisy source, so denoising costs some of the quality target.
+    Denoise(u32),
+    /// Synthesize photon noise for this ISO strength from a generated grain table.
+    Photon(u32),
+}
+
+#[derive(Default)]
+pub struct Av1Encoder {
+    pub film_grain: FilmGrain,
+}
 
 unsafe impl Send for Av1Encoder {}
 
 unsafe impl Sync for Av1Encoder {}
 
+impl Av1Encoder {
+    fn grain_args(&self, options: &EncoderOptions) -> Vec<String> {
+        match self.film_grain {
+            FilmGrain::Off => vec![],
+            FilmGrain::Denoise(level) => vec![format!("--denoise-noise-level={}", level)],
+            FilmGrain::Photon(_) => vec![format!("--film-grain-table={}", options.grain_table)],
+        }
+    }
+
+    // aomenc has no way to signal range or HDR metadata, mkvmerge adds those to the container instead.
+    fn color_args(color: &ColorInfo) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(primaries) = color.primaries_name(Naming::Aom) {
+            args.push(format!("--color-primaries={}", primaries));
+        }
+        if let Some(transfer) = color.transfer_name(Naming::Aom) {
+            args.push(format!("--transfer-characteristics={}", transfer));
+        }
+        if let Some(matrix) = color.matrix_name(Naming::Aom) {
+            args.push(format!("--matrix-coefficients={}", matrix));
+        }
+        args
+    }
+}
+
 impl Encoder for Av1Encoder {
+    fn quality_parameter(&self) -> QualityParameter {
+        QualityParameter {
+            name: "cq-level".to_string(),
+            min: 0,
+            max: 63,
+            direction: QualityDirection::LowerIsBetter,
+            initial_min: 20,
+            initial_max: 40,
+        }
+    }
+
+    fn passes(&self) -> Passes {
+        Passes::Two
+    }
+
+    // There is no --version, the version is only listed at the bottom of the help
+    fn version_args(&self) -> Vec<&str> {
+        vec!["--help"]
+    }
+
+    fn parse_version(&self, output: &str) -> Option<String> {
+        included_encoder_version(output)
+    }
+
+    fn prepare_scene(&self, options: EncoderOptions) -> Option<Command> {
+        match self.film_grain {
+            FilmGrain::Photon(iso) => {
+                let mut c = Command::new("photon_noise_table");
+                c.arg(format!("--width={}", options.width))
+                    .arg(format!("--height={}", options.height))
+                    .arg(format!("--iso={}", iso))
+                    .arg(format!("--output={}", options.grain_table));
+                Some(c)
+            }
+            _ => None,
+        }
+    }
+
+    fn synthesizes_grain(&self) -> bool {
+        self.film_grain != FilmGrain::Off
+    }
+
     fn first_pass(&self, options: EncoderOptions) -> Command {
         let mut c = Command::new("aomenc");
         c.arg("--quiet")
@@ -15,7 +100,8 @@ impl Encoder for Av1Encoder {
             .arg("--passes=2")
             .arg("--pass=1")
             .arg("-b")
-            .arg("10")
+            .arg(options.bit_depth.to_string())
+            .arg(format!("--input-bit-depth={}", options.input_bit_depth))
             .arg("--kf-max-dist=250")
             .arg("--lag-in-frames=48")
             .arg("--enable-fwd-kf=1")
@@ -26,6 +112,7 @@ impl Encoder for Av1Encoder {
             .arg(format!("--threads={}", options.threads))
             .arg(format!("--fpf={}", options.log_file))
             .arg("--end-usage=q")
+            .args(self.grain_args(&options))
             .arg("-o")
             .arg("/dev/null")
             .arg(options.input);
@@ -50,9 +137,16 @@ impl Encoder for Av1Encoder {
             .arg("--kf-max-dist=250")
             .arg("--arnr-strength=0")
             .arg(format!("--threads={}", options.threads))
+            .arg(format!("--tile-columns={}", options.tile_columns_log2))
+            .arg(format!("--tile-rows={}", options.tile_rows_log2))
+            .arg("--row-mt=1")
             .arg("-b")
-            .arg("10")
+            .arg(options.bit_depth.to_string())
+            .arg(format!("--input-bit-depth={}", options.input_bit_depth))
             .arg("--end-usage=q")
+            .args(self.grain_args(&options))
+            .args(Av1Encoder::color_args(options.color))
+            .args(options.lossless.then_some("--lossless=1"))
             .arg("--ivf")
             .arg("-o")
             .arg(options.output)
@@ -60,3 +154,70 @@ impl Encoder for Av1Encoder {
         return c;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::av1_encoder::{Av1Encoder, FilmGrain};
+    use crate::encoder::{Encoder, EncoderOptions};
+
+    fn args(encoder: &Av1Encoder) -> Vec<String> {
+        encoder
+            .second_pass(EncoderOptions {
+                grain_table: "scene.tbl",
+                ..Default::default()
+            })
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn no_film_grain() {
+        let encoder = Av1Encoder::default();
+        assert!(!encoder.synthesizes_grain());
+        assert!(encoder.prepare_scene(EncoderOptions::default()).is_none());
+        assert!(!args(&encoder)
+            .iter()
+            .any(|arg| arg.starts_with("--denoise") || arg.starts_with("--film-grain")));
+    }
+
+    #[test]
+    fn denoised_film_grain() {
+        let encoder = Av1Encoder {
+            film_grain: FilmGrain::Denoise(8),
+        };
+        assert!(encoder.synthesizes_grain());
+        assert!(encoder.prepare_scene(EncoderOptions::default()).is_none());
+        assert!(args(&encoder).contains(&"--denoise-noise-level=8".to_string()));
+    }
+
+    #[test]
+    fn photon_noise() {
+        let encoder = Av1Encoder {
+            film_grain: FilmGrain::Photon(800),
+        };
+        assert!(encoder.synthesizes_grain());
+        assert!(args(&encoder).contains(&"--film-grain-table=scene.tbl".to_string()));
+        let prepare = encoder
+            .prepare_scene(EncoderOptions {
+                width: 1920,
+                height: 1080,
+                grain_table: "scene.tbl",
+                ..Default::default()
+            })
+            .unwrap();
+        let prepare = prepare.as_std();
+        assert_eq!(prepare.get_program(), "photon_noise_table");
+        let prepare_args: Vec<_> = prepare.get_args().collect();
+        assert_eq!(
+            prepare_args,
+            [
+                "--width=1920",
+                "--height=1080",
+                "--iso=800",
+                "--output=scene.tbl"
+            ]
+        );
+    }
+}
@@ -0,0 +1,428 @@
+use serde_json::Value;
+
+/// The names a single ITU-T H.273 code point goes by in ffprobe (which x264 and x265 share), aomenc and rav1e.
+struct CodePoint {
+    code: u32,
+    ffmpeg: &'static str,
+    aom: &'static str,
+    rav1e: &'static str,
+}
+
+const fn cp(code: u32, ffmpeg: &'static str, aom: &'static str, rav1e: &'static str) -> CodePoint {
+    CodePoint {
+        code,
+        ffmpeg,
+        aom,
+        rav1e,
+    }
+}
+
+const PRIMARIES: [CodePoint; 12] = [
+    cp(1, "bt709", "bt709", "BT709"),
+    cp(4, "bt470m", "bt470m", "BT470M"),
+    cp(5, "bt470bg", "bt470bg", "BT470BG"),
+    cp(6, "smpte170m", "bt601", "BT601"),
+    cp(7, "smpte240m", "smpte240", "SMPTE240"),
+    cp(8, "film", "film", "GenericFilm"),
+    cp(9, "bt2020", "bt2020", "BT2020"),
+    cp(10, "smpte428", "xyz", "XYZ"),
+    cp(11, "smpte431", "smpte431", "SMPTE431"),
+    cp(12, "smpte432", "smpte432", "SMPTE432"),
+    cp(22, "jedec-p22", "ebu3213", "EBU3213"),
+    // Newer ffprobe calls jedec-p22 by its EBU name
+    cp(22, "ebu3213", "ebu3213", "EBU3213"),
+];
+
+const TRANSFERS: [CodePoint; 16] = [
+    cp(1, "bt709", "bt709", "BT709"),
+    cp(4, "gamma22", "bt470m", "BT470M"),
+    cp(5, "gamma28", "bt470bg", "BT470BG"),
+    cp(6, "smpte170m", "bt601", "BT601"),
+    cp(7, "smpte240m", "smpte240", "SMPTE240"),
+    cp(8, "linear", "lin", "Linear"),
+    cp(9, "log100", "log100", "Log100"),
+    cp(10, "log316", "log100sq10", "Log100Sqrt10"),
+    cp(11, "iec61966-2-4", "iec61966", "IEC61966"),
+    cp(12, "bt1361e", "bt1361", "BT1361"),
+    cp(13, "iec61966-2-1", "srgb", "SRGB"),
+    cp(14, "bt2020-10", "bt2020-10bit", "BT2020_10Bit"),
+    cp(15, "bt2020-12", "bt2020-12bit", "BT2020_12Bit"),
+    cp(16, "smpte2084", "smpte2084", "SMPTE2084"),
+    cp(17, "smpte428", "smpte428", "SMPTE428"),
+    cp(18, "arib-std-b67", "hlg", "HLG"),
+];
+
+const MATRICES: [CodePoint; 14] = [
+    cp(0, "gbr", "identity", "Identity"),
+    cp(1, "bt709", "bt709", "BT709"),
+    cp(4, "fcc", "fcc73", "FCC"),
+    cp(5, "bt470bg", "bt470bg", "BT470BG"),
+    cp(6, "smpte170m", "bt601", "BT601"),
+    cp(7, "smpte240m", "smpte240", "SMPTE240"),
+    cp(8, "ycgco", "ycgco", "YCgCo"),
+    cp(9, "bt2020nc", "bt2020ncl", "BT2020NCL"),
+    cp(10, "bt2020c", "bt2020cl", "BT2020CL"),
+    cp(11, "smpte2085", "smpte2085", "SMPTE2085"),
+    cp(12, "chroma-derived-nc", "chromncl", "ChromatNCL"),
+    cp(13, "chroma-derived-c", "chromcl", "ChromatCL"),
+    cp(14, "ictcp", "ictcp", "ICtCp"),
+    // ffprobe reports bt470bg matrices from some containers as bt601
+    cp(5, "bt601", "bt470bg", "BT470BG"),
+];
+
+/// Which encoder's spelling to use for a code point.
+#[derive(Copy, Clone)]
+pub enum Naming {
+    Ffmpeg,
+    Aom,
+    Rav1e,
+}
+
+fn lookup(table: &[CodePoint], code: Option<u32>, naming: Naming) -> Option<&'static str> {
+    let code = code?;
+    table.iter().find(|c| c.code == code).map(|c| match naming {
+        Naming::Ffmpeg => c.ffmpeg,
+        Naming::Aom => c.aom,
+        Naming::Rav1e => c.rav1e,
+    })
+}
+
+fn code(table: &[CodePoint], ffmpeg_name: &Value) -> Option<u32> {
+    let name = ffmpeg_name.as_str()?;
+    table.iter().find(|c| c.ffmpeg == name).map(|c| c.code)
+}
+
+/// ffprobe reports side data values as rationals like "34000/50000"
+fn rational(value: &Value) -> Option<f64> {
+    if let Some(n) = value.as_f64() {
+        return Some(n);
+    }
+    let mut parts = value.as_str()?.split('/');
+    let numerator: f64 = parts.next()?.parse().ok()?;
+    let denominator: f64 = parts.next().unwrap_or("1").parse().ok()?;
+    if denominator == 0.0 {
+        None
+    } else {
+        Some(numerator / denominator)
+    }
+}
+
+/// SMPTE ST 2086 mastering display colour volume. Chromaticities are CIE 1931 xy, luminance is in cd/m².
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MasteringDisplay {
+    pub red: (f64, f64),
+    pub green: (f64, f64),
+    pub blue: (f64, f64),
+    pub white_point: (f64, f64),
+    pub max_luminance: f64,
+    pub min_luminance: f64,
+}
+
+impl MasteringDisplay {
+    fn from_side_data(side_data: &Value) -> Option<MasteringDisplay> {
+        let xy = |x: &str, y: &str| Some((rational(&side_data[x])?, rational(&side_data[y])?));
+        Some(MasteringDisplay {
+            red: xy("red_x", "red_y")?,
+            green: xy("green_x", "green_y")?,
+            blue: xy("blue_x", "blue_y")?,
+            white_point: xy("white_point_x", "white_point_y")?,
+            max_luminance: rational(&side_data["max_luminance"])?,
+            min_luminance: rational(&side_data["min_luminance"])?,
+        })
+    }
+
+    /// `G(x,y)B(x,y)R(x,y)WP(x,y)L(max,min)` written out as decimals, the way SvtAv1EncApp and rav1e take it.
+    pub fn decimal(&self) -> String {
+        format!(
+            "G({:.4},{:.4})B({:.4},{:.4})R({:.4},{:.4})WP({:.4},{:.4})L({:.4},{:.4})",
+            self.green.0,
+            self.green.1,
+            self.blue.0,
+            self.blue.1,
+            self.red.0,
+            self.red.1,
+            self.white_point.0,
+            self.white_point.1,
+            self.max_luminance,
+            self.min_luminance
+        )
+    }
+
+    /// `G(x,y)B(x,y)R(x,y)WP(x,y)L(max,min)` in the integer units of the HEVC SEI message, which x265 wants:
+    /// chromaticities in 0.00002 steps and luminance in 0.0001 cd/m² steps.
+    pub fn sei(&self) -> String {
+        let c = |v: f64| (v * 50000.0).round() as u64;
+        let l = |v: f64| (v * 10000.0).round() as u64;
+        format!(
+            "G({},{})B({},{})R({},{})WP({},{})L({},{})",
+            c(self.green.0),
+            c(self.green.1),
+            c(self.blue.0),
+            c(self.blue.1),
+            c(self.red.0),
+            c(self.red.1),
+            c(self.white_point.0),
+            c(self.white_point.1),
+            l(self.max_luminance),
+            l(self.min_luminance)
+        )
+    }
+}
+
+/// Content light level in cd/m².
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ContentLight {
+    pub max_cll: u32,
+    pub max_fall: u32,
+}
+
+/// Colour description of the source video, kept as ITU-T H.273 code points.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ColorInfo {
+    pub primaries: Option<u32>,
+    pub transfer: Option<u32>,
+    pub matrix: Option<u32>,
+    pub full_range: Option<bool>,
+    pub mastering_display: Option<MasteringDisplay>,
+    pub content_light: Option<ContentLight>,
+}
+
+pub static UNKNOWN_COLOR: ColorInfo = ColorInfo {
+    primaries: None,
+    transfer: None,
+    matrix: None,
+    full_range: None,
+    mastering_display: None,
+    content_light: None,
+};
+
+impl ColorInfo {
+    /// A fully described HDR10 source, which makes the encoders use every colour flag they have.
+    pub fn hdr10() -> ColorInfo {
+        ColorInfo {
+            primaries: Some(9),
+            transfer: Some(16),
+            matrix: Some(9),
+            full_range: Some(false),
+            mastering_display: Some(MasteringDisplay {
+                red: (0.708, 0.292),
+                green: (0.17, 0.797),
+                blue: (0.131, 0.046),
+                white_point: (0.3127, 0.329),
+                max_luminance: 1000.0,
+                min_luminance: 0.0001,
+            }),
+            content_light: Some(ContentLight {
+                max_cll: 1000,
+                max_fall: 400,
+            }),
+        }
+    }
+
+    /// Reads the colour description of the first video stream out of `ffprobe -show_streams -show_frames` json.
+    /// HDR side data is taken from the stream when the container has it and from the first frame otherwise.
+    pub fn from_probe(probe: &Value) -> ColorInfo {
+        let empty = vec![];
+        let streams = probe["streams"].as_array().unwrap_or(&empty);
+        let video = match streams.iter().find(|s| s["codec_type"] == "video") {
+            Some(video) => video,
+            None => return ColorInfo::default(),
+        };
+        let frame_side_data = probe["frames"]
+            .as_array()
+            .unwrap_or(&empty)
+            .iter()
+            .find(|f| f["stream_index"] == video["index"])
+            .and_then(|f| f["side_data_list"].as_array());
+        let side_data: Vec<&Value> = video["side_data_list"]
+            .as_array()
+            .into_iter()
+            .chain(frame_side_data)
+            .flatten()
+            .collect();
+        let find = |side_data_type: &str| {
+            side_data
+                .iter()
+                .find(|s| s["side_data_type"] == side_data_type)
+                .copied()
+        };
+
+        ColorInfo {
+            primaries: code(&PRIMARIES, &video["color_primaries"]),
+            transfer: code(&TRANSFERS, &video["color_transfer"]),
+            matrix: code(&MATRICES, &video["color_space"]),
+            full_range: match video["color_range"].as_str() {
+                Some("pc") => Some(true),
+                Some("tv") => Some(false),
+                _ => None,
+            },
+            mastering_display: find("Mastering display metadata")
+                .and_then(MasteringDisplay::from_side_data),
+            content_light: find("Content light level metadata").and_then(|s| {
+                Some(ContentLight {
+                    max_cll: s["max_content"].as_u64()? as u32,
+                    max_fall: s["max_average"].as_u64()? as u32,
+                })
+            }),
+        }
+    }
+
+    pub fn primaries_name(&self, naming: Naming) -> Option<&'static str> {
+        lookup(&PRIMARIES, self.primaries, naming)
+    }
+
+    pub fn transfer_name(&self, naming: Naming) -> Option<&'static str> {
+        lookup(&TRANSFERS, self.transfer, naming)
+    }
+
+    pub fn matrix_name(&self, naming: Naming) -> Option<&'static str> {
+        lookup(&MATRICES, self.matrix, naming)
+    }
+
+    /// mkvmerge colour options for the video track, these have to come before the file they apply to.
+    pub fn mkvmerge_options(&self) -> Vec<String> {
+        let mut options = vec![];
+        let mut push = |option: &str, value: String| {
+            options.push(option.to_string());
+            options.push(format!("0:{}", value));
+        };
+        if let Some(matrix) = self.matrix {
+            push("--colour-matrix-coefficients", matrix.to_string());
+        }
+        if let Some(full_range) = self.full_range {
+            push(
+                "--colour-range",
+                if full_range { "2" } else { "1" }.to_string(),
+            );
+        }
+        if let Some(transfer) = self.transfer {
+            push("--colour-transfer-characteristics", transfer.to_string());
+        }
+        if let Some(primaries) = self.primaries {
+            push("--colour-primaries", primaries.to_string());
+        }
+        if let Some(light) = self.content_light {
+            push("--max-content-light", light.max_cll.to_string());
+            push("--max-frame-light", light.max_fall.to_string());
+        }
+        if let Some(display) = self.mastering_display {
+            push(
+                "--chromaticity-coordinates",
+                format!(
+                    "{},{},{},{},{},{}",
+                    display.red.0,
+                    display.red.1,
+                    display.green.0,
+                    display.green.1,
+                    display.blue.0,
+                    display.blue.1
+                ),
+            );
+            push(
+                "--white-colour-coordinates",
+                format!("{},{}", display.white_point.0, display.white_point.1),
+            );
+            push("--max-luminance", display.max_luminance.to_string());
+            push("--min-luminance", display.min_luminance.to_string());
+        }
+        options
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::{ColorInfo, Naming};
+    use serde_json::json;
+
+    fn hdr10() -> ColorInfo {
+        ColorInfo::from_probe(&json!({
+            "streams": [
+                {"index": 0, "codec_type": "audio"},
+                {
+                    "index": 1,
+                    "codec_type": "video",
+                    "color_range": "tv",
+                    "color_space": "bt2020nc",
+                    "color_transfer": "smpte2084",
+                    "color_primaries": "bt2020"
+                }
+            ],
+            "frames": [
+                {
+                    "stream_index": 1,
+                    "side_data_list": [
+                        {
+                            "side_data_type": "Mastering display metadata",
+                            "red_x": "34000/50000", "red_y": "16000/50000",
+                            "green_x": "13250/50000", "green_y": "34500/50000",
+                            "blue_x": "7500/50000", "blue_y": "3000/50000",
+                            "white_point_x": "15635/50000", "white_point_y": "16450/50000",
+                            "min_luminance": "50/10000", "max_luminance": "10000000/10000"
+                        },
+                        {"side_data_type": "Content light level metadata", "max_content": 1000, "max_average": 400}
+                    ]
+                }
+            ]
+        }))
+    }
+
+    #[test]
+    fn reads_hdr10() {
+        let color = hdr10();
+        assert_eq!(color.primaries, Some(9));
+        assert_eq!(color.transfer, Some(16));
+        assert_eq!(color.matrix, Some(9));
+        assert_eq!(color.full_range, Some(false));
+        assert_eq!(color.content_light.unwrap().max_cll, 1000);
+        assert_eq!(color.transfer_name(Naming::Aom), Some("smpte2084"));
+        assert_eq!(color.matrix_name(Naming::Rav1e), Some("BT2020NCL"));
+        let display = color.mastering_display.unwrap();
+        assert_eq!(
+            display.sei(),
+            "G(13250,34500)B(7500,3000)R(34000,16000)WP(15635,16450)L(10000000,50)"
+        );
+        assert_eq!(
+            display.decimal(),
+            "G(0.2650,0.6900)B(0.1500,0.0600)R(0.6800,0.3200)WP(0.3127,0.3290)L(1000.0000,0.0050)"
+        );
+    }
+
+    #[test]
+    fn mkvmerge_options() {
+        let options = hdr10().mkvmerge_options();
+        assert_eq!(
+            options[0..8],
+            [
+                "--colour-matrix-coefficients",
+                "0:9",
+                "--colour-range",
+                "0:1",
+                "--colour-transfer-characteristics",
+                "0:16",
+                "--colour-primaries",
+                "0:9"
+            ]
+        );
+        assert!(options.contains(&"0:1000".to_string()));
+    }
+
+    #[test]
+    fn untagged_source() {
+        let color =
+            ColorInfo::from_probe(&json!({"streams": [{"index": 0, "codec_type": "video"}]}));
+        assert_eq!(color, ColorInfo::default());
+        assert!(color.mkvmerge_options().is_empty());
+    }
+
+    #[test]
+    fn reads_both_ebu3213_spellings() {
+        for name in ["jedec-p22", "ebu3213"] {
+            let color = ColorInfo::from_probe(&json!({
+                "streams": [{"index": 0, "codec_type": "video", "color_primaries": name}]
+            }));
+            assert_eq!(color.primaries, Some(22));
+            assert_eq!(color.primaries_name(Naming::Ffmpeg), Some("jedec-p22"));
+            assert_eq!(color.primaries_name(Naming::Aom), Some("ebu3213"));
+        }
+    }
+}
@@ -1,17 +1,159 @@
+use crate::color::{ColorInfo, Naming, UNKNOWN_COLOR};
+use crate::video_header::VideoHeader;
+use lazy_static::lazy_static;
+use regex::Regex;
 use tokio::process::Command;
 
+/// Which end of an encoder's quality parameter produces the better looking encode.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum QualityDirection {
+    LowerIsBetter,
+    HigherIsBetter,
+}
+
+/// Whether an encoder runs a first pass writing stats to `log_file` before the second pass.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Passes {
+    One,
+    Two,
+}
+
+/// The knob an encoder exposes for trading quality against size: the flag name, the values it accepts,
+/// which way is better, and the pair of values the per scene search starts probing at.
+#[derive(Clone, Debug, PartialEq)]
+pub struct QualityParameter {
+    pub name: String,
+    pub min: u32,
+    pub max: u32,
+    pub direction: QualityDirection,
+    pub initial_min: u32,
+    pub initial_max: u32,
+}
+
+impl QualityParameter {
+    /// The value producing the smallest, worst looking encode.
+    pub fn worst(&self) -> u32 {
+        match self.direction {
+            QualityDirection::LowerIsBetter => self.max,
+            QualityDirection::HigherIsBetter => self.min,
+        }
+    }
+
+    /// The value producing the largest, best looking encode.
+    pub fn best(&self) -> u32 {
+        match self.direction {
+            QualityDirection::LowerIsBetter => self.min,
+            QualityDirection::HigherIsBetter => self.max,
+        }
+    }
+
+    /// Moves `value` a single step towards better quality without leaving the valid range.
+    pub fn better(&self, value: u32) -> u32 {
+        match self.direction {
+            QualityDirection::LowerIsBetter => value.saturating_sub(1).max(self.min),
+            QualityDirection::HigherIsBetter => (value + 1).min(self.max),
+        }
+    }
+
+    /// Moves `value` a single step towards worse quality without leaving the valid range.
+    #[allow(dead_code)]
+    pub fn worse(&self, value: u32) -> u32 {
+        match self.direction {
+            QualityDirection::LowerIsBetter => (value + 1).min(self.max),
+            QualityDirection::HigherIsBetter => value.saturating_sub(1).max(self.min),
+        }
+    }
+}
+
 pub trait Encoder {
-    fn first_pass(&self, options: EncoderOptions) -> Command;
+    fn quality_parameter(&self) -> QualityParameter;
+
+    /// Extension of the bitstream written by `second_pass`, used for each scene's artifact.
+    fn extension(&self) -> &str {
+        "ivf"
+    }
+
+    /// mkvmerge options that have to precede the scene files for mkvmerge to make sense of them.
+    /// IVF carries its own timing, so there is nothing to add by default.
+    fn mkvmerge_options(&self, _header: &VideoHeader) -> Vec<String> {
+        vec![]
+    }
+
+    fn passes(&self) -> Passes;
+
+    /// Bit depth to encode at when none is asked for, which normally is the depth of the source.
+    fn default_bit_depth(&self, input_bit_depth: u32) -> u32 {
+        input_bit_depth
+    }
+
+    /// Whether the encoder can encode at a bit depth other than the one of the y4m it reads.
+    fn converts_bit_depth(&self) -> bool {
+        true
+    }
+
+    /// A command to run once for each scene before any of its passes, such as generating side data they read in.
+    fn prepare_scene(&self, _options: EncoderOptions) -> Option<Command> {
+        None
+    }
+
+    /// Whether the encoded stream carries film grain for the decoder to synthesize.
+    /// The probes leave that grain out when scoring, random noise never matches the source's own noise.
+    fn synthesizes_grain(&self) -> bool {
+        false
+    }
+
+    /// Only called for two pass encoders.
+    fn first_pass(&self, _options: EncoderOptions) -> Command {
+        unreachable!("Single pass encoders have no first pass")
+    }
+
     fn second_pass(&self, options: EncoderOptions) -> Command;
+
+    /// Arguments that make the encoder list every flag it supports.
+    fn help_args(&self) -> Vec<&str> {
+        vec!["--help"]
+    }
+
+    /// Arguments that make the encoder print its version.
+    fn version_args(&self) -> Vec<&str> {
+        vec!["--version"]
+    }
+
+    /// Pulls the version out of whatever `version_args` printed.
+    fn parse_version(&self, output: &str) -> Option<String> {
+        first_version(output)
+    }
+
+    /// The second pass used by the quality probes, which normally is just a regular second pass.
+    fn probe(&self, options: EncoderOptions) -> Command {
+        self.second_pass(options)
+    }
 }
 
+#[derive(Copy, Clone)]
 pub struct EncoderOptions<'t> {
     pub cpu_used: u32,
     pub threads: u32,
+    /// Value for the encoder's `quality_parameter`, in whatever units that encoder uses.
     pub cq: u32,
     pub log_file: &'t str,
     pub input: &'t str,
     pub output: &'t str,
+    /// Where a per scene film grain table lives, see `Encoder::prepare_scene`.
+    pub grain_table: &'t str,
+    pub width: u32,
+    pub height: u32,
+    /// Tile layout, as the log2 of the number of tile columns and rows.
+    pub tile_columns_log2: u32,
+    pub tile_rows_log2: u32,
+    /// Encode without any loss, `cq` is the parameter's best value when set.
+    pub lossless: bool,
+    /// Bit depth to encode at.
+    pub bit_depth: u32,
+    /// Bit depth of the y4m going into the encoder, which can differ from `bit_depth`.
+    pub input_bit_depth: u32,
+    /// Colour description of the source, to be signalled in the bitstream.
+    pub color: &'t ColorInfo,
 }
 
 impl Default for EncoderOptions<'_> {
@@ -23,6 +165,111 @@ impl Default for EncoderOptions<'_> {
             log_file: "",
             input: "",
             output: "",
+            grain_table: "",
+            width: 0,
+            height: 0,
+            tile_columns_log2: 0,
+            tile_rows_log2: 0,
+            lossless: false,
+            bit_depth: 10,
+            input_bit_depth: 10,
+            color: &UNKNOWN_COLOR,
         }
     }
 }
+
+pub fn is_flag(arg: &str) -> bool {
+    arg.len() > 1 && arg.starts_with('-') && arg.parse::<f64>().is_err()
+}
+
+/// The name of a flag without any `=value` attached to it.
+pub fn flag_name(arg: &str) -> &str {
+    arg.split('=').next().unwrap()
+}
+
+/// The first thing that looks like a version number in a program's output.
+pub fn first_version(output: &str) -> Option<String> {
+    lazy_static! {
+        static ref VERSION_RE: Regex = Regex::new(r"v?(\d+\.\d+[\w.+-]*)").unwrap();
+    }
+    VERSION_RE
+        .captures(output)
+        .map(|captures| captures[1].to_string())
+}
+
+/// Parses the encoder version out of libaom/libvpx style help output, which lists the included encoders last.
+pub fn included_encoder_version(output: &str) -> Option<String> {
+    lazy_static! {
+        static ref INCLUDED_RE: Regex = Regex::new(r"Encoder\s+v?(\d+\.\d+[\w.+-]*)").unwrap();
+    }
+    INCLUDED_RE
+        .captures(output)
+        .map(|captures| captures[1].to_string())
+}
+
+/// mkvmerge options for raw Annex B streams, which have no timing information of their own.
+pub fn annex_b_mkvmerge_options(header: &VideoHeader) -> Vec<String> {
+    vec![
+        "--default-duration".to_string(),
+        format!("0:{}fps", header.rate.replace(':', "/")),
+    ]
+}
+
+/// Colour flags shared by x264 and x265, which use the same names for them as ffmpeg.
+/// Mastering display and content light metadata are x265 only.
+pub fn x26x_color_args(color: &ColorInfo) -> Vec<String> {
+    let mut args = vec![];
+    let mut push = |flag: &str, value: &str| {
+        args.push(flag.to_string());
+        args.push(value.to_string());
+    };
+    if let Some(primaries) = color.primaries_name(Naming::Ffmpeg) {
+        push("--colorprim", primaries);
+    }
+    if let Some(transfer) = color.transfer_name(Naming::Ffmpeg) {
+        push("--transfer", transfer);
+    }
+    if let Some(matrix) = color.matrix_name(Naming::Ffmpeg) {
+        push("--colormatrix", matrix);
+    }
+    if let Some(full_range) = color.full_range {
+        push("--range", if full_range { "full" } else { "limited" });
+    }
+    args
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::{QualityDirection, QualityParameter};
+
+    fn parameter(direction: QualityDirection) -> QualityParameter {
+        QualityParameter {
+            name: "test".to_string(),
+            min: 0,
+            max: 63,
+            direction,
+            initial_min: 20,
+            initial_max: 40,
+        }
+    }
+
+    #[test]
+    fn lower_is_better() {
+        let p = parameter(QualityDirection::LowerIsBetter);
+        assert_eq!(p.worst(), 63);
+        assert_eq!(p.best(), 0);
+        assert_eq!(p.better(30), 29);
+        assert_eq!(p.better(0), 0);
+        assert_eq!(p.worse(63), 63);
+    }
+
+    #[test]
+    fn higher_is_better() {
+        let p = parameter(QualityDirection::HigherIsBetter);
+        assert_eq!(p.worst(), 0);
+        assert_eq!(p.best(), 63);
+        assert_eq!(p.better(30), 31);
+        assert_eq!(p.better(63), 63);
+        assert_eq!(p.worse(30), 29);
+    }
+}
@@ -0,0 +1,222 @@
+use crate::encoder::{flag_name, is_flag, Encoder, EncoderOptions, Passes, QualityParameter};
+use crate::video_header::VideoHeader;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::process::Command;
+
+/// Wraps another encoder, layering user supplied arguments on top of the commands it builds.
+/// Flags given here replace the wrapped encoder's own value for that flag instead of being passed twice.
+pub struct ExtraArgsEncoder {
+    pub inner: Arc<dyn Encoder + Send + Sync>,
+    pub first_pass: Vec<String>,
+    pub second_pass: Vec<String>,
+    pub probe: Vec<String>,
+}
+
+unsafe impl Send for ExtraArgsEncoder {}
+
+unsafe impl Sync for ExtraArgsEncoder {}
+
+impl Encoder for ExtraArgsEncoder {
+    fn quality_parameter(&self) -> QualityParameter {
+        self.inner.quality_parameter()
+    }
+
+    fn extension(&self) -> &str {
+        self.inner.extension()
+    }
+
+    fn mkvmerge_options(&self, header: &VideoHeader) -> Vec<String> {
+        self.inner.mkvmerge_options(header)
+    }
+
+    fn passes(&self) -> Passes {
+        self.inner.passes()
+    }
+
+    fn default_bit_depth(&self, input_bit_depth: u32) -> u32 {
+        self.inner.default_bit_depth(input_bit_depth)
+    }
+
+    fn converts_bit_depth(&self) -> bool {
+        self.inner.converts_bit_depth()
+    }
+
+    fn prepare_scene(&self, options: EncoderOptions) -> Option<Command> {
+        self.inner.prepare_scene(options)
+    }
+
+    fn synthesizes_grain(&self) -> bool {
+        self.inner.synthesizes_grain()
+    }
+
+    fn first_pass(&self, options: EncoderOptions) -> Command {
+        with_extra_args(self.inner.first_pass(options), &self.first_pass)
+    }
+
+    fn second_pass(&self, options: EncoderOptions) -> Command {
+        with_extra_args(self.inner.second_pass(options), &self.second_pass)
+    }
+
+    fn help_args(&self) -> Vec<&str> {
+        self.inner.help_args()
+    }
+
+    fn version_args(&self) -> Vec<&str> {
+        self.inner.version_args()
+    }
+
+    fn parse_version(&self, output: &str) -> Option<String> {
+        self.inner.parse_version(output)
+    }
+
+    fn probe(&self, options: EncoderOptions) -> Command {
+        let second_pass = with_extra_args(self.inner.probe(options), &self.second_pass);
+        with_extra_args(second_pass, &self.probe)
+    }
+}
+
+/// Rebuilds `command` with `extra` in front of its arguments, dropping any of its own flags that `extra` sets.
+/// Flags are matched on their name, so `--kf-max-dist=120` replaces `--kf-max-dist=250` and `--crf 30` replaces
+/// `--crf 20` along with its value. Either way of writing the value replaces the other, so `--crf=30` also replaces
+/// `--crf 20`.
+pub fn with_extra_args(command: Command, extra: &[String]) -> Command {
+    if extra.is_empty() {
+        return command;
+    }
+    // Flag name -> whether it takes a value, written either way
+    let mut overridden = HashMap::new();
+    for (i, arg) in extra.iter().enumerate() {
+        if is_flag(arg) {
+            let takes_value = arg.contains('=') || extra.get(i + 1).is_some_and(|a| !is_flag(a));
+            overridden.insert(flag_name(arg), takes_value);
+        }
+    }
+
+    let original = command.as_std();
+    let mut c = Command::new(original.get_program());
+    c.args(extra);
+    let args: Vec<&str> = original.get_args().map(|a| a.to_str().unwrap()).collect();
+    let mut i = 0;
+    while i < args.len() {
+        let arg = args[i];
+        i += 1;
+        if is_flag(arg) {
+            if let Some(takes_value) = overridden.get(flag_name(arg)) {
+                // How the original wrote its value decides whether the next argument goes along with it. A switch
+                // takes no value, so whatever follows one is left alone.
+                if *takes_value && !arg.contains('=') && i < args.len() && !is_flag(args[i]) {
+                    i += 1;
+                }
+                continue;
+            }
+        }
+        c.arg(arg);
+    }
+    c
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::Encoder;
+    use crate::extra_args_encoder::{with_extra_args, ExtraArgsEncoder};
+    use crate::x264_encoder::X264Encoder;
+    use std::sync::Arc;
+    use tokio::process::Command;
+
+    fn args(c: &Command) -> Vec<&str> {
+        c.as_std().get_args().map(|a| a.to_str().unwrap()).collect()
+    }
+
+    #[test]
+    fn overrides_inline_values() {
+        let mut c = Command::new("aomenc");
+        c.arg("--kf-max-dist=250")
+            .arg("--quiet")
+            .arg("-b")
+            .arg("10")
+            .arg("in.y4m");
+        let extra = vec![
+            "--kf-max-dist=120".to_string(),
+            "--arnr-strength=2".to_string(),
+        ];
+        let c = with_extra_args(c, &extra);
+        assert_eq!(
+            args(&c),
+            vec![
+                "--kf-max-dist=120",
+                "--arnr-strength=2",
+                "--quiet",
+                "-b",
+                "10",
+                "in.y4m"
+            ]
+        );
+    }
+
+    #[test]
+    fn overrides_separate_values() {
+        let mut c = Command::new("SvtAv1EncApp");
+        c.arg("--crf")
+            .arg("20")
+            .arg("--progress")
+            .arg("0")
+            .arg("-i")
+            .arg("in.y4m");
+        let extra = vec![
+            "--crf".to_string(),
+            "30".to_string(),
+            "--tune".to_string(),
+            "0".to_string(),
+        ];
+        let c = with_extra_args(c, &extra);
+        assert_eq!(
+            args(&c),
+            vec![
+                "--crf",
+                "30",
+                "--tune",
+                "0",
+                "--progress",
+                "0",
+                "-i",
+                "in.y4m"
+            ]
+        );
+    }
+
+    #[test]
+    fn inline_value_replaces_separate_value() {
+        let mut c = Command::new("x264");
+        c.arg("--crf").arg("20").arg("--quiet").arg("in.y4m");
+        let c = with_extra_args(c, &["--crf=30".to_string()]);
+        assert_eq!(args(&c), vec!["--crf=30", "--quiet", "in.y4m"]);
+    }
+
+    #[test]
+    fn separate_value_replaces_inline_value() {
+        let mut c = Command::new("aomenc");
+        c.arg("--cq-level=20").arg("-b").arg("10").arg("in.y4m");
+        let c = with_extra_args(c, &["--cq-level".to_string(), "30".to_string()]);
+        assert_eq!(args(&c), vec!["--cq-level", "30", "-b", "10", "in.y4m"]);
+    }
+
+    #[test]
+    fn switches_keep_what_follows() {
+        let mut c = Command::new("aomenc");
+        c.arg("-o").arg("out.ivf").arg("--quiet").arg("in.y4m");
+        let c = with_extra_args(c, &["--quiet".to_string()]);
+        assert_eq!(args(&c), vec!["--quiet", "-o", "out.ivf", "in.y4m"]);
+    }
+
+    #[test]
+    fn keeps_default_bit_depth() {
+        let encoder = ExtraArgsEncoder {
+            inner: Arc::new(X264Encoder {}),
+            first_pass: vec![],
+            second_pass: vec![],
+            probe: vec![],
+        };
+        assert_eq!(encoder.default_bit_depth(10), 8);
+    }
+}
@@ -1,35 +1,71 @@
 mod aom_firstpass;
 mod av1_encoder;
+mod color;
 mod encoder;
+mod extra_args_encoder;
 mod frame;
 mod frame_buffer;
+mod plane_metric;
+mod preflight;
+mod probe_cache;
+mod quality_metric;
+mod rate_control;
+mod rav1e_encoder;
+mod report;
+mod sampling;
+mod search;
+mod svt_av1_encoder;
+mod template_encoder;
+mod threading;
 mod video_header;
 mod vp9_encoder;
+mod x264_encoder;
+mod x265_encoder;
 
 use crate::aom_firstpass::aom::AomFirstpass;
 use crate::frame::Status::Processing;
 use crate::frame_buffer::FrameBuffer;
+use crate::plane_metric::{ChromaFloor, ChromaMetric, PlaneScores, U, V, Y};
+use crate::preflight::{filter_names, preflight, preflight_ffmpeg, BinaryInfo, Tool};
+use crate::probe_cache::{ProbeCache, SceneHash};
+use crate::quality_metric::{
+    MetricOptions, NativePsnr, NativeSsim, Pooling, Psnr, QualityMetric, Ssimulacra2, Vmaf, Xpsnr,
+};
+use crate::rate_control::RateControl;
+use crate::report::{
+    wait_with_cpu, wait_with_output_and_cpu, write_report, PhaseTime, ProbeRecord, SceneReport,
+    Verification,
+};
+use crate::sampling::Sampling;
+use crate::search::{Search, SearchOptions, Strategy};
 use crate::video_header::VideoHeader;
 use clap::{App, Arg, ArgMatches};
-use lazy_static::lazy_static;
-use regex::{Captures, Regex};
-use serde_json::Value;
+use serde_json::{json, Value};
 
-use crate::av1_encoder::Av1Encoder;
-use crate::encoder::{Encoder, EncoderOptions};
+use crate::av1_encoder::{Av1Encoder, FilmGrain};
+use crate::color::ColorInfo;
+use crate::encoder::{Encoder, EncoderOptions, Passes, QualityParameter};
+use crate::extra_args_encoder::ExtraArgsEncoder;
+use crate::rav1e_encoder::Rav1eEncoder;
+use crate::svt_av1_encoder::SvtAv1Encoder;
+use crate::template_encoder::TemplateEncoder;
+use crate::threading::Threading;
 use crate::vp9_encoder::Vp9Encoder;
+use crate::x264_encoder::X264Encoder;
+use crate::x265_encoder::X265Encoder;
 use glob::glob;
 use std::collections::VecDeque;
 use std::convert::TryInto;
 use std::ops::{BitAnd, Not};
 use std::path::{Path, PathBuf};
-use std::process::Stdio;
+use std::process::{ExitStatus, Stdio};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::fs::File;
-use tokio::fs::{create_dir, remove_dir_all, remove_file};
+use tokio::fs::{create_dir, metadata, remove_dir_all, remove_file, rename};
 use tokio::io::{AsyncWriteExt, BufReader, ErrorKind};
 use tokio::join;
-use tokio::process::{Child, Command};
+use tokio::process::{Child, ChildStdout, Command};
 use tokio::sync::broadcast::{Receiver, Sender};
 use tokio::sync::{broadcast, Mutex, Semaphore};
 use tokio::task;
@@ -53,17 +89,125 @@ async fn main() {
 
     println!("Encoding {} files", targets.len());
 
-    let encoders = options.value_of_t_or_exit("encoders");
-    let cpu_used = options.value_of_t_or_exit("cpu_used");
-    let vmaf_cpu_used = options.value_of_t_or_exit("vmaf_cpu_used");
+    let encoders: usize = options.value_of_t_or_exit("encoders");
     let vpy: String = options.value_of_t_or_exit("vpy");
-    let vmaf_target: f64 = options.value_of_t_or_exit::<f64>("vmaf_target") / 100.0;
     let encoder_str: String = options.value_of_t_or_exit::<String>("codec");
-    let encoder: Arc<dyn Encoder + Send + Sync> = match encoder_str.as_str() {
-        "vpx" => Arc::new(Vp9Encoder {}),
-        "av1" => Arc::new(Av1Encoder {}),
+    let encoder: Arc<dyn Encoder + Send + Sync> = match options.value_of("encoder_config") {
+        Some(config) => Arc::new(
+            TemplateEncoder::read(config)
+                .unwrap_or_else(|e| panic!("Failed to load encoder config {}: {}", config, e)),
+        ),
+        None if film_grain(&options) != FilmGrain::Off && encoder_str != "av1" => {
+            panic!("--film_grain and --photon_noise only work with the av1 codec")
+        }
+        None => match encoder_str.as_str() {
+            "vpx" => Arc::new(Vp9Encoder {}),
+            "av1" => Arc::new(Av1Encoder {
+                film_grain: film_grain(&options),
+            }),
+            "svt" => Arc::new(SvtAv1Encoder {}),
+            "rav1e" => Arc::new(Rav1eEncoder {}),
+            "x265" => Arc::new(X265Encoder {}),
+            "x264" => Arc::new(X264Encoder {}),
+            _ => panic!("Shouldn't have gotten here"),
+        },
+    };
+    let encoder: Arc<dyn Encoder + Send + Sync> = Arc::new(ExtraArgsEncoder {
+        inner: encoder,
+        first_pass: split_args(&options, "first_pass_args"),
+        second_pass: split_args(&options, "second_pass_args"),
+        probe: split_args(&options, "probe_args"),
+    });
+    if options.is_present("bit_depth") && !encoder.converts_bit_depth() {
+        panic!(
+            "{} always encodes at the bit depth of the vapoursynth output, convert it there instead of using --bit_depth",
+            encoder_str
+        );
+    }
+    let lossless = options.is_present("lossless");
+    let metric: Box<dyn QualityMetric + Send + Sync> = match options.value_of("metric").unwrap() {
+        "vmaf" => Box::new(Vmaf {
+            pooling: Pooling::parse(options.value_of("pooling").unwrap()).unwrap_or_else(|| {
+                panic!("Pooling has to be mean, harmonic or a percentile like p5")
+            }),
+            model: options.value_of("vmaf_model").map(|m| m.to_string()),
+            neg: options.is_present("vmaf_neg"),
+        }),
+        "ssimulacra2" => Box::new(Ssimulacra2 {}),
+        "xpsnr" => Box::new(Xpsnr {}),
+        "psnr" => Box::new(Psnr {}),
+        "native_psnr" => Box::new(NativePsnr {}),
+        "native_ssim" => Box::new(NativeSsim {}),
         _ => panic!("Shouldn't have gotten here"),
     };
+    let vmaf_target = quality_target(&options, metric.as_ref());
+    let mut settings = Settings {
+        cpu_used: options.value_of_t_or_exit("cpu_used"),
+        vmaf_cpu_used: options.value_of_t_or_exit("vmaf_cpu_used"),
+        vmaf_target: vmaf_target / 100.0,
+        metric,
+        chroma_floor: options.value_of("chroma_floor").map(|_| ChromaFloor {
+            metric: match options.value_of("chroma_metric").unwrap() {
+                "psnr" => ChromaMetric::Psnr,
+                "ssim" => ChromaMetric::Ssim,
+                _ => panic!("Shouldn't have gotten here"),
+            },
+            floor: options.value_of_t_or_exit("chroma_floor"),
+        }),
+        search: SearchOptions {
+            strategy: Strategy::parse(options.value_of("search").unwrap())
+                .expect("Shouldn't have gotten here"),
+            tolerance: options.value_of_t_or_exit("search_tolerance"),
+            max_iterations: options.value_of_t_or_exit("search_iterations"),
+        },
+        probe_cache: match options.value_of("probe_cache") {
+            Some(path) => Some(ProbeCache::load(PathBuf::from(path)).await),
+            None => None,
+        },
+        tool_versions: String::new(),
+        keep_probes: options.is_present("keep_probes"),
+        verify_tolerance: options
+            .is_present("verify")
+            .then(|| options.value_of_t_or_exit::<f64>("verify_tolerance") / 100.0),
+        sampling: options.value_of("sample_clips").map(|_| {
+            let clip_frames = options.value_of_t_or_exit("sample_frames");
+            if clip_frames == 0 {
+                panic!("--sample_frames has to be at least 1");
+            }
+            Sampling {
+                clips: options.value_of_t_or_exit("sample_clips"),
+                clip_frames,
+            }
+        }),
+        bitrate: target_bitrate(&options, &targets).await,
+        fixed_cq: if lossless {
+            Some(encoder.quality_parameter().best())
+        } else {
+            options.value_of("cq").map(|_| {
+                let cq = options.value_of_t_or_exit("cq");
+                let parameter = encoder.quality_parameter();
+                if cq < parameter.min || cq > parameter.max {
+                    panic!(
+                        "--cq has to be between {} and {} for {}",
+                        parameter.min, parameter.max, parameter.name
+                    );
+                }
+                cq
+            })
+        },
+        lossless,
+        bit_depth: options
+            .value_of("bit_depth")
+            .map(|_| options.value_of_t_or_exit("bit_depth")),
+        encoders,
+    };
+    settings.tool_versions = check_binaries(encoder.as_ref(), &settings)
+        .await
+        .iter()
+        .map(|binary| format!("{} {}", binary.program, binary.version))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let settings = Arc::new(settings);
     let active_encodes = Arc::new(Semaphore::new(encoders));
 
     let mut tasks = vec![];
@@ -76,20 +220,10 @@ async fn main() {
         let entry = entry.clone();
         let cdn = can_do_next.clone();
         let e = encoder.clone();
+        let settings = settings.clone();
 
         tasks.push(tokio::spawn(async move {
-            compress_file(
-                cpu_used,
-                vmaf_cpu_used,
-                vpy,
-                vmaf_target,
-                active_encodes,
-                entry,
-                cdn,
-                len,
-                e,
-            )
-            .await
+            compress_file(settings, vpy, active_encodes, entry, cdn, len, e).await
         }));
         can_do_next.acquire().await.unwrap().forget()
     }
@@ -99,11 +233,240 @@ async fn main() {
     }
 }
 
-async fn compress_file(
+/// Runs the preflight checks for everything an encode is going to run, with the flags the settings will pass to it,
+/// printing the versions.
+async fn check_binaries(
+    encoder: &(dyn Encoder + Send + Sync),
+    settings: &Settings,
+) -> Vec<BinaryInfo> {
+    let color = ColorInfo::hdr10();
+    let sample = EncoderOptions {
+        log_file: "preflight.log",
+        input: "preflight.y4m",
+        output: "preflight.out",
+        grain_table: "preflight.tbl",
+        width: 1920,
+        height: 1080,
+        tile_columns_log2: 1,
+        tile_rows_log2: 1,
+        lossless: settings.lossless,
+        bit_depth: match settings.bit_depth {
+            Some(bit_depth) => bit_depth,
+            None if settings.lossless => 10,
+            None => encoder.default_bit_depth(10),
+        },
+        input_bit_depth: 10,
+        color: &color,
+        ..Default::default()
+    };
+    let mut commands = vec![];
+    if let Some(prepare) = encoder.prepare_scene(sample) {
+        commands.push((
+            prepare,
+            Tool::Other {
+                help_args: vec!["--help"],
+            },
+        ));
+    }
+    if encoder.passes() == Passes::Two {
+        commands.push((encoder.first_pass(sample), Tool::Encoder(encoder)));
+    }
+    commands.push((encoder.second_pass(sample), Tool::Encoder(encoder)));
+    commands.push((encoder.probe(sample), Tool::Encoder(encoder)));
+
+    let aomenc = Av1Encoder::default();
+    let mut scene_detection = Command::new("aomenc");
+    scene_detection.args(aom_scene_detection_args("/tmp", 10, 10));
+    commands.push((scene_detection, Tool::Encoder(&aomenc)));
+
+    let metric_options = MetricOptions {
+        reference: "preflight.y4m",
+        log_file: "preflight.json",
+        height: 1080,
+        threads: 1,
+    };
+    if let Some(scorer) = settings.metric.scorer(metric_options) {
+        commands.push((
+            scorer,
+            Tool::Other {
+                help_args: settings.metric.scorer_help_args(),
+            },
+        ));
+    }
+    let filters = settings
+        .metric
+        .filter(metric_options)
+        .map(|filter| filter_names(&filter))
+        .unwrap_or_default();
+
+    let (binaries, ffmpeg) = join!(preflight(commands), preflight_ffmpeg(&filters));
+    let mut binaries = binaries.unwrap_or_else(|e| panic!("Preflight failed: {}", e));
+    binaries.extend(ffmpeg.unwrap_or_else(|e| panic!("Preflight failed: {}", e)));
+    for binary in &binaries {
+        println!("Using {} {}", binary.program, binary.version);
+    }
+    binaries
+}
+
+/// Options that apply to every file being encoded.
+struct Settings {
     cpu_used: u32,
     vmaf_cpu_used: u32,
-    vpy: String,
     vmaf_target: f64,
+    /// What `vmaf_target` is measured with.
+    metric: Box<dyn QualityMetric + Send + Sync>,
+    /// Least the chroma planes have to score alongside `vmaf_target`.
+    chroma_floor: Option<ChromaFloor>,
+    search: SearchOptions,
+    probe_cache: Option<ProbeCache>,
+    /// Every program the encode runs along with its version, which the probe cache keys include.
+    tool_versions: String,
+    /// Probes that would encode the same as the second pass write their bitstream to disk, so it can stand in for it.
+    keep_probes: bool,
+    /// How far under the target a scene's final encode may score before it gets re-encoded, when verifying them.
+    verify_tolerance: Option<f64>,
+    /// Clips long scenes are probed on instead of the whole scene, when searching for a vmaf target.
+    sampling: Option<Sampling>,
+    /// Bits per second, when encoding to a bitrate rather than a vmaf target.
+    bitrate: Option<f64>,
+    /// Quality parameter value every scene gets, skipping the search.
+    fixed_cq: Option<u32>,
+    lossless: bool,
+    bit_depth: Option<u32>,
+    encoders: usize,
+}
+
+/// Most times a scene failing verification gets re-encoded before settling for what it has.
+const MAX_REENCODES: usize = 2;
+
+/// What every scene of a file needs to know to be encoded.
+struct FileContext {
+    settings: Arc<Settings>,
+    tmp_folder: String,
+    header: VideoHeader,
+    /// Bit depth of the encodes, which the source is converted to when it differs.
+    bit_depth: u32,
+    /// Every encoder process holds an encoder permit, so this splits the cores between as many of them.
+    threading: Threading,
+    /// Set when encoding to a bitrate rather than a vmaf target.
+    rate_control: Option<Mutex<RateControl>>,
+    color: ColorInfo,
+    encoder: Arc<dyn Encoder + Send + Sync>,
+    /// What happened to each scene finished so far, written out once the file is done.
+    reports: Mutex<Vec<SceneReport>>,
+}
+
+/// The files belonging to a single scene in the temp folder.
+struct ScenePaths {
+    /// The scene's number in the temp folder, which the other paths add an extension to.
+    base: String,
+    log_file: String,
+    input: String,
+    output: String,
+    grain_table: String,
+}
+
+impl FileContext {
+    fn scene_paths(&self, scene_number: u32) -> ScenePaths {
+        let scene_str = format!("{}/{:06}", self.tmp_folder, scene_number);
+        ScenePaths {
+            base: scene_str.clone(),
+            log_file: format!("{}.log", scene_str),
+            input: format!("{}.y4m", scene_str),
+            output: format!("{}.{}", scene_str, self.encoder.extension()),
+            grain_table: format!("{}.tbl", scene_str),
+        }
+    }
+
+    /// The clips cut out of a long scene to probe, which share the scene's grain table.
+    fn sample_paths(&self, scene_number: u32) -> ScenePaths {
+        let scene_str = format!("{}/{:06}", self.tmp_folder, scene_number);
+        let sample_str = format!("{}.sample", scene_str);
+        ScenePaths {
+            base: sample_str.clone(),
+            log_file: format!("{}.log", sample_str),
+            input: format!("{}.y4m", sample_str),
+            output: format!("{}.{}", sample_str, self.encoder.extension()),
+            grain_table: format!("{}.tbl", scene_str),
+        }
+    }
+
+    /// Whether the scene's probes encode clips out of it rather than all of it.
+    fn sampled(&self, scene_number: u32) -> bool {
+        Path::new(&self.sample_paths(scene_number).input).exists()
+    }
+
+    /// The files the scene's probes work on.
+    fn probe_paths(&self, scene_number: u32) -> ScenePaths {
+        if self.sampled(scene_number) {
+            self.sample_paths(scene_number)
+        } else {
+            self.scene_paths(scene_number)
+        }
+    }
+
+    /// Where a probe of the scene at `cq` writes its bitstream with `--keep_probes`, when it is the very same encode
+    /// the second pass would make at that value so the second pass can be skipped. That takes probing at `cpu_used`
+    /// with nothing added to the probe's command. The probe writes to the file itself rather than to a pipe, which
+    /// the encoders can't seek back in to fill in the frame count of the header.
+    fn probe_output(&self, scene_number: u32, cq: u32, cpu_used: u32) -> Option<String> {
+        if !self.settings.keep_probes {
+            return None;
+        }
+        let paths = self.scene_paths(scene_number);
+        let probe_paths = self.probe_paths(scene_number);
+        let kept = format!("{}.{}.probe.{}", paths.base, cq, self.encoder.extension());
+        // Sampled probes read another input, so they never match
+        let probe = self.encoder.probe(EncoderOptions {
+            cq,
+            cpu_used,
+            output: &kept,
+            ..self.options(&probe_paths)
+        });
+        let second_pass = self.encoder.second_pass(EncoderOptions {
+            cq,
+            cpu_used: self.settings.cpu_used,
+            output: &kept,
+            ..self.options(&paths)
+        });
+        (command_line(&probe) == command_line(&second_pass)).then_some(kept)
+    }
+
+    /// Encoder options for a scene, with everything but the per encode settings filled in.
+    fn options<'t>(&'t self, paths: &'t ScenePaths) -> EncoderOptions<'t> {
+        EncoderOptions {
+            log_file: paths.log_file.as_str(),
+            input: paths.input.as_str(),
+            output: paths.output.as_str(),
+            grain_table: paths.grain_table.as_str(),
+            threads: self.threading.threads,
+            width: self.header.width,
+            height: self.header.height,
+            tile_columns_log2: self.threading.tile_columns_log2,
+            tile_rows_log2: self.threading.tile_rows_log2,
+            bit_depth: self.bit_depth,
+            lossless: self.settings.lossless,
+            input_bit_depth: self.header.bit_depth(),
+            color: &self.color,
+            ..Default::default()
+        }
+    }
+}
+
+/// The program and arguments of `command`, separated by spaces.
+fn command_line(command: &Command) -> String {
+    let command = command.as_std();
+    let mut line = command.get_program().to_string_lossy().to_string();
+    for arg in command.get_args() {
+        line.push(' ');
+        line.push_str(&arg.to_string_lossy());
+    }
+    line
+}
+
+async fn compress_file(
+    settings: Arc<Settings>,
+    vpy: String,
     active_encoders: Arc<Semaphore>,
     input_path: PathBuf,
     can_do_next: Arc<Semaphore>,
@@ -115,7 +478,14 @@ async fn compress_file(
     let tmp_folder = format!("/tmp/{}", processed_file);
     create_dir(&tmp_folder).await.unwrap();
 
-    let audio_processing = encode_audio(i.clone(), active_encoders.clone(), tmp_folder.clone());
+    let source = probe_source(&i).await;
+    let color = ColorInfo::from_probe(&source);
+    let audio_processing = encode_audio(
+        i.clone(),
+        source,
+        active_encoders.clone(),
+        tmp_folder.clone(),
+    );
 
     let mut vspipe = start_vspipe(i.clone().as_str(), vpy.as_str(), tmp_folder.clone());
     let vspipe_output = vspipe.stdout.take().unwrap();
@@ -123,8 +493,35 @@ async fn compress_file(
 
     let analyzed_aom_frames = Arc::new(Semaphore::new(0));
     let header = VideoHeader::read(&mut vs_pipe_reader).await.unwrap();
+    // Lossless encodes keep the source's depth rather than the encoder's default
+    let bit_depth = match settings.bit_depth {
+        Some(bit_depth) => bit_depth,
+        None if settings.lossless => header.bit_depth(),
+        None => encoder.default_bit_depth(header.bit_depth()),
+    };
+    if settings.lossless && bit_depth < header.bit_depth() {
+        panic!(
+            "Can't encode {} losslessly at {} bit, it is {} bit",
+            i,
+            bit_depth,
+            header.bit_depth()
+        );
+    }
 
     let buffer = Arc::new(FrameBuffer::new(129, header.clone()));
+    let context = Arc::new(FileContext {
+        tmp_folder: tmp_folder.clone(),
+        header: header.clone(),
+        bit_depth,
+        threading: Threading::for_machine(header.width, header.height, settings.encoders as u32),
+        rate_control: settings
+            .bitrate
+            .map(|b| Mutex::new(RateControl::new(b, header.frame_rate()))),
+        color,
+        encoder,
+        settings,
+        reports: Mutex::new(vec![]),
+    });
 
     let delayed_aom = analyzed_aom_frames.clone();
     let (stats_tx, stats_rx) = broadcast::channel(129);
@@ -135,17 +532,13 @@ async fn compress_file(
     let processing = process(
         stats_rx,
         buffer.clone(),
-        header.clone(),
         active_encoders.clone(),
-        vmaf_target,
-        cpu_used,
-        vmaf_cpu_used,
-        tmp_folder.clone(),
-        encoder,
+        context.clone(),
     );
 
     let aom_first_pass_scene = aom_firstpass_for_scene_detection(
-        header,
+        header.clone(),
+        context.bit_depth,
         analyzed_aom_frames,
         buffer.clone(),
         tmp_folder.clone(),
@@ -163,12 +556,23 @@ async fn compress_file(
     frame_stats_processor.await.unwrap();
     audio_processing.await.unwrap();
 
-    concat(input_path, tmp_folder.clone(), scenes).await;
+    concat(input_path.clone(), scenes, &context).await;
+    if let Some(cache) = &context.settings.probe_cache {
+        cache.flush().await;
+    }
+    let mut reports = context.reports.lock().await;
+    reports.sort_by_key(|report| report.scene);
+    let output = input_path.with_extension("new.mkv");
+    write_report(Path::new(output.file_name().unwrap()), &i, &reports)
+        .await
+        .expect("Failed to write the encode report");
+    drop(reports);
     println!("Cleaning up temp folder");
     remove_dir_all(tmp_folder).await.unwrap();
 }
 
-async fn concat(input_path: PathBuf, tmp_folder: String, scenes: u32) {
+async fn concat(input_path: PathBuf, scenes: u32, context: &FileContext) {
+    let tmp_folder = &context.tmp_folder;
     let output_name = String::from(
         input_path
             .with_extension("new.mkv")
@@ -184,9 +588,16 @@ async fn concat(input_path: PathBuf, tmp_folder: String, scenes: u32) {
 
     options.push(format!("{}/audio.mkv", tmp_folder));
 
+    options.extend(context.encoder.mkvmerge_options(&context.header));
+    options.extend(context.color.mkvmerge_options());
     options.push("[".to_string());
     for scene in 0..=scenes {
-        let concat_line = format!("{}/{:06}.ivf", tmp_folder, scene);
+        let concat_line = format!(
+            "{}/{:06}.{}",
+            tmp_folder,
+            scene,
+            context.encoder.extension()
+        );
         options.push(concat_line);
     }
     options.push("]".to_string());
@@ -213,24 +624,98 @@ async fn concat(input_path: PathBuf, tmp_folder: String, scenes: u32) {
         .unwrap();
 }
 
-fn encode_audio(i: String, permits: Arc<Semaphore>, tmp_folder: String) -> JoinHandle<()> {
+/// Quality each scene has to reach, which only has a default for metrics with a well known scale.
+/// Encodes that don't search for a quality don't need one.
+fn quality_target(options: &ArgMatches, metric: &dyn QualityMetric) -> f64 {
+    if options.value_of("vmaf_target").is_some() {
+        return options.value_of_t_or_exit("vmaf_target");
+    }
+    let searches_quality = ["target_bitrate", "target_size", "cq", "lossless"]
+        .iter()
+        .all(|option| !options.is_present(option));
+    match metric.default_target() {
+        Some(target) => target,
+        None if searches_quality => panic!("--metric {} needs a --vmaf_target", metric.name()),
+        None => 0.0,
+    }
+}
+
+/// Bits per second to encode at when a bitrate or a total size is given instead of a vmaf target.
+/// A total size is spread over the combined duration of every input.
+async fn target_bitrate(options: &ArgMatches, targets: &[PathBuf]) -> Option<f64> {
+    if options.value_of("target_bitrate").is_some() {
+        let kbps: f64 = options.value_of_t_or_exit("target_bitrate");
+        return Some(kbps * 1000.0);
+    }
+    if options.value_of("target_size").is_some() {
+        let megabytes: f64 = options.value_of_t_or_exit("target_size");
+        let mut duration = 0.0;
+        for target in targets {
+            duration += source_duration(target.to_str().unwrap()).await;
+        }
+        if duration <= 0.0 {
+            panic!("The inputs have no duration to spread --target_size over");
+        }
+        return Some(megabytes * 8_000_000.0 / duration);
+    }
+    None
+}
+
+/// Length of the source in seconds.
+async fn source_duration(i: &str) -> f64 {
+    let probe_results = Command::new("ffprobe")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .arg("-hide_banner")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_entries")
+        .arg("format=duration")
+        .arg(i)
+        .spawn()
+        .unwrap()
+        .wait_with_output()
+        .await
+        .unwrap();
+
+    let probe_result: Value = serde_json::from_slice(&probe_results.stdout).unwrap_or_default();
+    probe_result["format"]["duration"]
+        .as_str()
+        .and_then(|d| d.parse().ok())
+        .unwrap_or_else(|| panic!("Could not read the duration of {}", i))
+}
+
+/// Runs ffprobe over the source, listing its streams and the first frame of each for the side data that only
+/// shows up on frames, like HDR metadata in some containers.
+async fn probe_source(i: &str) -> Value {
+    let probe_results = Command::new("ffprobe")
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .arg("-hide_banner")
+        .arg("-print_format")
+        .arg("json")
+        .arg("-show_streams")
+        .arg("-show_frames")
+        .arg("-read_intervals")
+        .arg("%+#1")
+        .arg(i)
+        .spawn()
+        .unwrap()
+        .wait_with_output()
+        .await
+        .unwrap();
+
+    serde_json::from_slice(&probe_results.stdout).unwrap()
+}
+
+fn encode_audio(
+    i: String,
+    probe_result: Value,
+    permits: Arc<Semaphore>,
+    tmp_folder: String,
+) -> JoinHandle<()> {
     task::spawn(async move {
         permits.acquire().await.unwrap().forget();
-        let probe_results = Command::new("ffprobe")
-            .stdout(Stdio::piped())
-            .stderr(Stdio::null())
-            .arg("-hide_banner")
-            .arg("-print_format")
-            .arg("json")
-            .arg("-show_streams")
-            .arg(&i)
-            .spawn()
-            .unwrap()
-            .wait_with_output()
-            .await
-            .unwrap();
-
-        let probe_result: Value = serde_json::from_slice(&probe_results.stdout).unwrap();
         let streams = probe_result["streams"].as_array().unwrap();
 
         let mut audio_encode = Command::new("ffmpeg");
@@ -285,15 +770,12 @@ fn encode_audio(i: String, permits: Arc<Semaphore>, tmp_folder: String) -> JoinH
 fn process(
     mut stats_rx: Receiver<FrameStats>,
     scene_buffer: Arc<FrameBuffer>,
-    header: VideoHeader,
     active_encodes_vpx: Arc<Semaphore>,
-    vmaf_target: f64,
-    cpu_used: u32,
-    vmaf_cpu_used: u32,
-    tmp_folder: String,
-    encoder: Arc<dyn Encoder + Send + Sync>,
+    context: Arc<FileContext>,
 ) -> JoinHandle<u32> {
     task::spawn(async move {
+        let tmp_folder = &context.tmp_folder;
+        let header = &context.header;
         let mut scene: u32 = 0;
         let mut file = File::create(format!("{}/{:06}.y4m", tmp_folder, scene))
             .await
@@ -301,6 +783,8 @@ fn process(
         let prior_cq_values: Arc<Mutex<Vec<u32>>> = Arc::new(Mutex::new(Vec::new()));
         header.clone().write(&mut file).await.unwrap();
         let mut inflight_scenes = vec![];
+        let mut scene_stats = SceneStats::default();
+        scene_stats.hash.update(&header.as_bytes);
         while let Ok(stat) = stats_rx.recv().await {
             if stat.is_keyframe {
                 file.flush().await.unwrap();
@@ -309,26 +793,31 @@ fn process(
                 inflight_scenes.push(
                     compress_scene(
                         scene,
+                        scene_stats,
                         active_encodes_vpx.clone(),
                         prior_cq_values.clone(),
-                        vmaf_target,
-                        cpu_used,
-                        vmaf_cpu_used,
-                        tmp_folder.clone(),
-                        encoder.clone(),
+                        context.clone(),
                     )
                     .await,
                 );
                 scene += 1;
+                scene_stats = SceneStats {
+                    start_frame: stat.frame_num,
+                    ..Default::default()
+                };
                 file = File::create(format!("{}/{:06}.y4m", tmp_folder, scene))
                     .await
                     .unwrap();
                 header.clone().write(&mut file).await.unwrap();
+                scene_stats.hash.update(&header.as_bytes);
             }
             let frame = scene_buffer.get_frame(stat.frame_num).await;
             if let Some(frame_data) = frame {
                 assert_eq!(stat.frame_num, frame_data.num);
                 frame_data.write(&mut file).await.unwrap();
+                scene_stats.frames += 1;
+                scene_stats.complexity += stat.complexity;
+                scene_stats.hash.update(frame_data.data());
                 scene_buffer.pop().await;
                 unsafe {
                     libmimalloc_sys::mi_collect(true);
@@ -341,13 +830,10 @@ fn process(
         inflight_scenes.push(
             compress_scene(
                 scene,
+                scene_stats,
                 active_encodes_vpx.clone(),
                 prior_cq_values.clone(),
-                vmaf_target,
-                cpu_used,
-                vmaf_cpu_used,
-                tmp_folder.clone(),
-                encoder,
+                context.clone(),
             )
             .await,
         );
@@ -360,265 +846,1016 @@ fn process(
 
 async fn compress_scene(
     scene_number: u32,
+    scene_stats: SceneStats,
     encoding_scenes: Arc<Semaphore>,
     prior_cq_values: Arc<Mutex<Vec<u32>>>,
-    vmaf_target: f64,
-    cpu_used: u32,
-    vmaf_cpu_used: u32,
-    tmp_folder: String,
-    encoder: Arc<dyn Encoder + Send + Sync>,
+    context: Arc<FileContext>,
 ) -> JoinHandle<()> {
+    if let Some(rate_control) = &context.rate_control {
+        rate_control.lock().await.add_scene(
+            scene_number,
+            scene_stats.frames,
+            scene_stats.complexity,
+        );
+    }
     encoding_scenes.acquire_many(2).await.unwrap().forget();
     tokio::spawn(async move {
-        let mut first_pass = first_pass(scene_number, tmp_folder.clone(), encoder.clone()).await;
-        first_pass.wait().await.unwrap();
-        let mut initial_min = 20;
-        let mut initial_max = 40;
-        {
-            let guard = prior_cq_values.lock().await;
-            if guard.len() >= 10 {
-                let sample_point = guard.len() / 10;
-                if guard[sample_point] != guard[guard.len() - sample_point - 1] {
-                    initial_min = guard[sample_point];
-                    initial_max = guard[guard.len() - sample_point - 1];
-                }
+        let first_pass_start = Instant::now();
+        let prepared = prepare_scene(scene_number, &context).await;
+        let passes = context.encoder.passes();
+        let mut first_pass_cpu = Duration::ZERO;
+        if passes == Passes::Two {
+            let mut first_pass = first_pass(scene_number, &context).await;
+            let (status, cpu) = wait_with_cpu(&mut first_pass).await.unwrap();
+            assert!(
+                status.success(),
+                "First pass of scene {} failed: {}",
+                scene_number,
+                status
+            );
+            first_pass_cpu = cpu;
+        }
+        let first_pass_time = PhaseTime {
+            wall: first_pass_start.elapsed(),
+            cpu: first_pass_cpu,
+        };
+        let search_start = Instant::now();
+        let sample_cpu = match (context.settings.fixed_cq, &context.rate_control) {
+            (None, None) => sample_scene(scene_number, scene_stats, &context).await,
+            _ => Duration::ZERO,
+        };
+        let (cq, probes, missed_target) = match context.settings.fixed_cq {
+            Some(cq) => (cq, vec![], false),
+            None => {
+                find_cq(
+                    scene_number,
+                    scene_stats,
+                    &prior_cq_values,
+                    0.0,
+                    context.clone(),
+                )
+                .await
             }
-            drop(guard);
+        };
+        let search_time = PhaseTime {
+            wall: search_start.elapsed(),
+            cpu: sample_cpu + probes.iter().map(|probe| probe.cpu).sum(),
+        };
+        // Verifying can search and encode the scene again, which takes both permits like the first search did
+        let verifying = context.settings.verify_tolerance.is_some();
+        if !verifying {
+            encoding_scenes.add_permits(1);
         }
-        let cq = vmaf_secant_search(
-            10,
-            60,
-            initial_min,
-            initial_max,
-            vmaf_cpu_used,
-            vmaf_target,
-            scene_number,
-            tmp_folder.clone(),
-            encoder.clone(),
-        )
-        .await;
-        encoding_scenes.add_permits(1);
         {
             let mut guard = prior_cq_values.lock().await;
             let insertion_index = guard.binary_search(&cq).unwrap_or_else(|x| x);
             guard.insert(insertion_index, cq);
             drop(guard);
         }
-        let mut second = second_pass(
+        let second_pass_start = Instant::now();
+        let mut second_pass_cpu = Duration::ZERO;
+        if !reuse_probe(scene_number, cq, &context).await {
+            let mut second =
+                second_pass(scene_number, cq, context.settings.cpu_used, &context).await;
+            let (status, cpu) = wait_with_cpu(&mut second).await.unwrap();
+            assert!(
+                status.success(),
+                "Second pass of scene {} failed: {}",
+                scene_number,
+                status
+            );
+            second_pass_cpu = cpu;
+        }
+        let second_pass_time = PhaseTime {
+            wall: second_pass_start.elapsed(),
+            cpu: second_pass_cpu,
+        };
+        let mut verification = VerifiedScene {
+            cq,
+            missed_target,
+            scores: vec![],
+            probes,
+            time: PhaseTime::default(),
+        };
+        if verifying {
+            verify_scene(
+                scene_number,
+                scene_stats,
+                &mut verification,
+                &prior_cq_values,
+                context.clone(),
+            )
+            .await;
+            encoding_scenes.add_permits(1);
+        }
+        let paths = context.scene_paths(scene_number);
+        let size = metadata(&paths.output).await.unwrap().len();
+        if let Some(rate_control) = &context.rate_control {
+            // The probe at the final value and the probes' usual speed shows how far off their sizes are
+            let probe_bits = verification
+                .probes
+                .iter()
+                .rev()
+                .find(|probe| {
+                    probe.cq == verification.cq
+                        && probe.cpu_used == context.settings.vmaf_cpu_used
+                        && !probe.sampled
+                })
+                .map(|probe| probe.score);
+            rate_control
+                .lock()
+                .await
+                .finished(scene_number, (size * 8) as f64, probe_bits);
+        }
+        let seconds = scene_stats.frames as f64 / context.header.frame_rate();
+        context.reports.lock().await.push(SceneReport {
+            scene: scene_number,
+            start_frame: scene_stats.start_frame,
+            frames: scene_stats.frames,
+            probes: verification.probes,
+            cq: verification.cq,
+            missed_target: verification.missed_target,
+            verified: verification.scores,
+            size,
+            bitrate: if seconds > 0.0 {
+                (size * 8) as f64 / seconds
+            } else {
+                0.0
+            },
+            first_pass: first_pass_time,
+            search: search_time,
+            second_pass: second_pass_time,
+            verify: verification.time,
+        });
+        // Keep the scene's probes on disk right away, so a crash later on doesn't lose them
+        if let Some(cache) = &context.settings.probe_cache {
+            cache.flush().await;
+        }
+        encoding_scenes.add_permits(1);
+        cleanup(scene_number, &context, passes, prepared).await;
+    })
+}
+
+/// Searches for the quality parameter value that hits the scene's target, starting from where most earlier scenes
+/// ended up.
+async fn find_cq(
+    scene_number: u32,
+    scene_stats: SceneStats,
+    prior_cq_values: &Mutex<Vec<u32>>,
+    quality_boost: f64,
+    context: Arc<FileContext>,
+) -> (u32, Vec<ProbeRecord>, bool) {
+    let parameter = context.encoder.quality_parameter();
+    let mut initial_min = parameter.initial_min;
+    let mut initial_max = parameter.initial_max;
+    {
+        let guard = prior_cq_values.lock().await;
+        if guard.len() >= 10 {
+            let sample_point = guard.len() / 10;
+            if guard[sample_point] != guard[guard.len() - sample_point - 1] {
+                initial_min = guard[sample_point];
+                initial_max = guard[guard.len() - sample_point - 1];
+            }
+        }
+        drop(guard);
+    }
+    // Budgets are handed out once the scene gets to run so they include the corrections from every scene
+    // finished before it.
+    let target = match &context.rate_control {
+        Some(rate_control) => {
+            SceneTarget::Bits(rate_control.lock().await.probe_target(scene_number))
+        }
+        None => SceneTarget::Quality(context.settings.vmaf_target + quality_boost),
+    };
+    search_cq(
+        initial_min,
+        initial_max,
+        target,
+        scene_number,
+        scene_stats.hash,
+        context,
+    )
+    .await
+}
+
+/// How a scene's final encode came out, and what it took to get it to meet the target.
+struct VerifiedScene {
+    cq: u32,
+    /// The search that picked `cq` had no probe meet its target.
+    missed_target: bool,
+    scores: Vec<Verification>,
+    probes: Vec<ProbeRecord>,
+    time: PhaseTime,
+}
+
+/// Scores the scene's final encode. Probes run at `vmaf_cpu_used` and the final encode at `cpu_used`, so the final
+/// encode can come out worse than its probes said, as can a scene whose probes only saw clips out of it. While it
+/// misses the target by more than the tolerance the search is run again with the target raised by the miss, or by
+/// how far the sampled probe was off when that is more, mostly out of the probe cache, and the scene re-encoded, up
+/// to `MAX_REENCODES` times.
+async fn verify_scene(
+    scene_number: u32,
+    scene_stats: SceneStats,
+    verification: &mut VerifiedScene,
+    prior_cq_values: &Mutex<Vec<u32>>,
+    context: Arc<FileContext>,
+) {
+    let start = Instant::now();
+    let settings = &context.settings;
+    let tolerance = settings.verify_tolerance.unwrap();
+    let parameter = context.encoder.quality_parameter();
+    let paths = context.scene_paths(scene_number);
+    let mut quality_boost = 0.0;
+    loop {
+        let cq = verification.cq;
+        let (score, planes, score_cpu) = score_scene(
             scene_number,
             cq,
-            cpu_used,
-            tmp_folder.clone(),
-            encoder.clone(),
+            Stdio::null(),
+            &paths.output,
+            &paths,
+            &context,
         )
         .await;
-        second.wait().await.unwrap();
-        drop(second);
-        encoding_scenes.add_permits(1);
-        cleanup(scene_number, tmp_folder).await;
-    })
+        verification.time.cpu += score_cpu;
+        let chroma = settings
+            .chroma_floor
+            .zip(planes)
+            .map(|(floor, planes)| floor.score(&planes));
+        let distance = quality_distance(
+            scene_number,
+            cq,
+            score,
+            chroma,
+            settings.vmaf_target,
+            settings,
+        );
+        println!(
+            "{}: final encode at {} {} scored {} against a target of {}",
+            scene_number, parameter.name, cq, score, settings.vmaf_target
+        );
+        verification.scores.push(Verification {
+            cq,
+            score: score * 100.0,
+            chroma,
+        });
+        // Sampled probes only saw clips out of the scene, how far they were off at this value calibrates the search
+        let calibration = verification
+            .probes
+            .iter()
+            .rev()
+            .find(|probe| probe.sampled && probe.cq == cq)
+            .map(|probe| probe.score / 100.0 - score);
+        if let Some(offset) = calibration {
+            println!(
+                "{}: sampled probes at {} {} were {:+.4} off the full scene",
+                scene_number, parameter.name, cq, offset
+            );
+        }
+        if distance >= -tolerance
+            || cq == parameter.best()
+            || verification.scores.len() > MAX_REENCODES
+        {
+            break;
+        }
+
+        quality_boost = match calibration {
+            Some(offset) => offset.max(quality_boost - distance),
+            None => quality_boost - distance,
+        };
+        let (found, probes, missed_target) = find_cq(
+            scene_number,
+            scene_stats,
+            prior_cq_values,
+            quality_boost,
+            context.clone(),
+        )
+        .await;
+        verification.time.cpu += probes.iter().map(|probe| probe.cpu).sum();
+        verification.probes.extend(probes);
+        verification.missed_target = missed_target;
+        // The search can land back on the same value when the miss is down to noise, but the encode has to improve
+        verification.cq = if found.abs_diff(parameter.best()) < cq.abs_diff(parameter.best()) {
+            found
+        } else {
+            parameter.better(cq)
+        };
+        println!(
+            "{}: re-encoding at {} {}",
+            scene_number, parameter.name, verification.cq
+        );
+        if !reuse_probe(scene_number, verification.cq, &context).await {
+            let mut encode =
+                second_pass(scene_number, verification.cq, settings.cpu_used, &context).await;
+            let (status, cpu) = wait_with_cpu(&mut encode).await.unwrap();
+            assert!(
+                status.success(),
+                "Re-encode of scene {} failed: {}",
+                scene_number,
+                status
+            );
+            verification.time.cpu += cpu;
+        }
+    }
+    verification.time.wall = start.elapsed();
 }
 
-async fn first_pass(
+/// Cuts the clips to probe out of a scene long enough to be sampled, running a first pass over them for two pass
+/// encoders. Returns the CPU time of that first pass.
+async fn sample_scene(
     scene_number: u32,
-    tmp_folder: String,
-    encoder: Arc<dyn Encoder + Send + Sync>,
-) -> Child {
-    let scene_str = format!("{}/{:06}", tmp_folder, scene_number);
-    encoder
+    scene_stats: SceneStats,
+    context: &FileContext,
+) -> Duration {
+    let sampling = match context.settings.sampling {
+        Some(sampling) if sampling.applies(scene_stats.frames) => sampling,
+        _ => return Duration::ZERO,
+    };
+    let paths = context.scene_paths(scene_number);
+    let sample_paths = context.sample_paths(scene_number);
+    // Written under another name first, the probes go by the sample's input existing
+    let partial = format!("{}.partial", sample_paths.input);
+    let mut reader = BufReader::new(File::open(&paths.input).await.unwrap());
+    let mut writer = File::create(&partial).await.unwrap();
+    let frames = sampling
+        .extract(scene_stats.frames, &mut reader, &mut writer)
+        .await
+        .unwrap_or_else(|err| panic!("Failed to sample scene {}: {}", scene_number, err));
+    rename(&partial, &sample_paths.input).await.unwrap();
+    println!(
+        "{}: probing {} of its {} frames",
+        scene_number, frames, scene_stats.frames
+    );
+    if context.encoder.passes() != Passes::Two {
+        return Duration::ZERO;
+    }
+    let mut first_pass = context
+        .encoder
         .first_pass(EncoderOptions {
-            log_file: format!("{}.log", scene_str).as_str(),
-            input: format!("{}.y4m", scene_str).as_str(),
             output: "/dev/null",
-            ..Default::default()
+            ..context.options(&sample_paths)
+        })
+        .spawn()
+        .unwrap();
+    let (status, cpu) = wait_with_cpu(&mut first_pass).await.unwrap();
+    assert!(
+        status.success(),
+        "First pass of scene {}'s sample failed: {}",
+        scene_number,
+        status
+    );
+    cpu
+}
+
+/// Runs the encoder's per scene preparation, if it has any, returning whether it ran.
+async fn prepare_scene(scene_number: u32, context: &FileContext) -> bool {
+    let paths = context.scene_paths(scene_number);
+    let preparation = context.encoder.prepare_scene(context.options(&paths));
+    match preparation {
+        Some(mut command) => {
+            let status = command.spawn().unwrap().wait().await.unwrap();
+            assert!(
+                status.success(),
+                "Preparing scene {} failed: {}",
+                scene_number,
+                status
+            );
+            true
+        }
+        None => false,
+    }
+}
+
+async fn first_pass(scene_number: u32, context: &FileContext) -> Child {
+    let paths = context.scene_paths(scene_number);
+    context
+        .encoder
+        .first_pass(EncoderOptions {
+            output: "/dev/null",
+            ..context.options(&paths)
         })
         .spawn()
         .unwrap()
 }
 
-async fn second_pass(
-    scene_number: u32,
-    cq: u32,
-    cpu_used: u32,
-    tmp_folder: String,
-    encoder: Arc<dyn Encoder + Send + Sync>,
-) -> Child {
-    let scene_str = format!("{}/{:06}", tmp_folder, scene_number);
-    encoder
+async fn second_pass(scene_number: u32, cq: u32, cpu_used: u32, context: &FileContext) -> Child {
+    let paths = context.scene_paths(scene_number);
+    context
+        .encoder
         .second_pass(EncoderOptions {
-            cq: cq,
-            cpu_used: cpu_used,
-            log_file: format!("{}.log", scene_str).as_str(),
-            input: format!("{}.y4m", scene_str).as_str(),
-            output: format!("{}.ivf", scene_str).as_str(),
-            ..Default::default()
+            cq,
+            cpu_used,
+            ..context.options(&paths)
         })
         .spawn()
         .unwrap()
 }
 
-async fn cleanup(scene_number: u32, tmp_folder: String) {
-    let scene_str = format!("{}/{:06}", tmp_folder, scene_number);
-    let remove_video = remove_file(format!("{}.y4m", scene_str));
-    let remove_scene = remove_file(format!("{}.log", scene_str));
-    let (video, scene) = join!(remove_video, remove_scene);
-    video.unwrap();
-    scene.unwrap();
+/// Makes the bitstream a probe kept at `cq` the scene's output, returning false when no probe kept one.
+async fn reuse_probe(scene_number: u32, cq: u32, context: &FileContext) -> bool {
+    let kept = match context.probe_output(scene_number, cq, context.settings.cpu_used) {
+        Some(kept) if Path::new(&kept).exists() => kept,
+        _ => return false,
+    };
+    let paths = context.scene_paths(scene_number);
+    rename(&kept, &paths.output).await.unwrap();
+    println!(
+        "{}: using the probe at {} as the final encode",
+        scene_number, cq
+    );
+    true
+}
+
+async fn cleanup(scene_number: u32, context: &FileContext, passes: Passes, prepared: bool) {
+    let paths = context.scene_paths(scene_number);
+    let kept_probes = format!("{}.*.probe.{}", paths.base, context.encoder.extension());
+    for kept in glob(&kept_probes).unwrap().flatten() {
+        remove_file(kept).await.unwrap();
+    }
+    if context.sampled(scene_number) {
+        let sample_paths = context.sample_paths(scene_number);
+        remove_file(sample_paths.input).await.unwrap();
+        if passes == Passes::Two {
+            remove_file(sample_paths.log_file).await.unwrap();
+        }
+    }
+    remove_file(paths.input).await.unwrap();
+    if passes == Passes::Two {
+        remove_file(paths.log_file).await.unwrap();
+    }
+    if prepared {
+        remove_file(paths.grain_table).await.unwrap();
+    }
 }
 
-async fn vmaf_second_pass(
+async fn quality_second_pass(
     scene_number: u32,
     cq: u32,
     cpu_used: u32,
-    threads: u32,
-    tmp_folder: String,
-    encoder: Arc<dyn Encoder + Send + Sync>,
-) -> f64 {
-    let scene_str = format!("{}/{:06}", tmp_folder, scene_number);
-    let mut encode = encoder
-        .second_pass(EncoderOptions {
+    context: Arc<FileContext>,
+) -> (f64, Option<PlaneScores>, Duration) {
+    let paths = context.probe_paths(scene_number);
+    // A probe that can stand in for the second pass writes its bitstream out, and gets scored from there
+    if let Some(kept) = context.probe_output(scene_number, cq, cpu_used) {
+        let mut encode = context
+            .encoder
+            .probe(EncoderOptions {
+                cq,
+                cpu_used,
+                output: &kept,
+                ..context.options(&paths)
+            })
+            .spawn()
+            .unwrap();
+        let (encode_status, cpu) = wait_with_cpu(&mut encode).await.unwrap();
+        check_probe(scene_number, cq, encode_status);
+        let (score, planes, score_cpu) =
+            score_scene(scene_number, cq, Stdio::null(), &kept, &paths, &context).await;
+        return (score, planes, cpu + score_cpu);
+    }
+    let mut encode = context
+        .encoder
+        .probe(EncoderOptions {
             cq,
             cpu_used,
-            threads,
-            log_file: format!("{}.log", scene_str).as_str(),
             output: "-",
-            input: format!("{}.y4m", scene_str).as_str(),
-            ..Default::default()
+            ..context.options(&paths)
         })
         .stdout(Stdio::piped())
         .spawn()
         .unwrap();
-
-    let encode_stdin: Stdio = encode
+    let bitstream: Stdio = encode
         .stdout
         .take()
         .unwrap()
         .try_into()
         .expect("failed to convert to Stdio");
+    let (encode_result, (score, planes, score_cpu)) = join!(
+        wait_with_cpu(&mut encode),
+        score_scene(scene_number, cq, bitstream, "pipe:0", &paths, &context)
+    );
+    let (encode_status, cpu) = encode_result.unwrap();
+    check_probe(scene_number, cq, encode_status);
+    (score, planes, cpu + score_cpu)
+}
 
-    let ffmpeg = Command::new("ffmpeg")
-        .stdin(encode_stdin)
-        .stdout(Stdio::null())
-        .stderr(Stdio::piped())
-        .arg("-y")
-        .arg("-i")
-        .arg("pipe:0")
-        .arg("-i")
-        .arg(format!("{}.y4m", scene_str))
-        .arg("-threads")
-        .arg("1")
-        .arg("-lavfi")
-        .arg("libvmaf=model='path=/usr/local/share/model/vmaf_v0.6.1.json'")
-        .arg("-f")
-        .arg("null")
-        .arg("-")
+/// Scores an encode of the scene against the input of `paths`, which is the sample for sampled probes, with ffmpeg
+/// reading the encode from `input`, which is `pipe:0` for one coming in on `stdin`. Returns the score out of 1, along
+/// with the planes of the encode when they were compared for the metric or the chroma floor, and the CPU time the
+/// decode and scoring took.
+async fn score_scene(
+    scene_number: u32,
+    cq: u32,
+    stdin: Stdio,
+    input: &str,
+    paths: &ScenePaths,
+    context: &FileContext,
+) -> (f64, Option<PlaneScores>, Duration) {
+    let metric = &context.settings.metric;
+    let metric_log = format!("{}.{}.json", paths.base, cq);
+    let metric_options = MetricOptions {
+        reference: paths.input.as_str(),
+        log_file: metric_log.as_str(),
+        height: context.header.height,
+        threads: context.threading.threads,
+    };
+    let mut ffmpeg = Command::new("ffmpeg");
+    ffmpeg.stdin(stdin).stderr(Stdio::piped()).arg("-y");
+    if context.encoder.synthesizes_grain() {
+        // Have the decoder hand back the grain parameters instead of applying them, so the probe scores the
+        // encode itself rather than how closely random grain lines up with the source's noise.
+        // When aomenc denoised the scene that leaves its noise out of the encode but not out of the reference,
+        // which is why the denoise level is capped at MAX_DENOISE_LEVEL.
+        ffmpeg.arg("-export_side_data").arg("film_grain");
+    }
+    ffmpeg.arg("-i").arg(input);
+    // Metrics need both sides in the same pixel format, so bring the encode back to the source's bit depth
+    let pix_fmt = context.header.pix_fmt();
+    let wants_planes = context.settings.chroma_floor.is_some();
+    let (score, planes, cpu) = match metric.filter(metric_options) {
+        Some(filter) => {
+            ffmpeg
+                .arg("-i")
+                .arg(&paths.input)
+                .arg("-threads")
+                .arg("1")
+                .arg("-lavfi");
+            if wants_planes {
+                // Split off a copy of the decode so its planes can be compared here while the filter scores it
+                ffmpeg
+                    .stdout(Stdio::piped())
+                    .arg(format!(
+                        "[0:v]format={},split[distorted][planes];{}[scored]",
+                        pix_fmt, filter
+                    ))
+                    .arg("-map")
+                    .arg("[scored]")
+                    .arg("-f")
+                    .arg("null")
+                    .arg("-")
+                    .arg("-map")
+                    .arg("[planes]")
+                    .arg("-strict")
+                    .arg("-1")
+                    .arg("-f")
+                    .arg("yuv4mpegpipe")
+                    .arg("pipe:1");
+            } else {
+                ffmpeg
+                    .stdout(Stdio::null())
+                    .arg(format!("[0:v]format={}[distorted];{}", pix_fmt, filter))
+                    .arg("-f")
+                    .arg("null")
+                    .arg("-");
+            }
+            let mut ffmpeg = ffmpeg.spawn().unwrap();
+            let decoded = ffmpeg.stdout.take();
+            let (ffmpeg_output, planes) = join!(wait_with_output_and_cpu(ffmpeg), async {
+                match decoded {
+                    Some(decoded) => {
+                        Some(compare_planes(&paths.input, decoded, tokio::io::sink()).await)
+                    }
+                    None => None,
+                }
+            });
+            let (ffmpeg_output, cpu) = ffmpeg_output.unwrap();
+            let output = String::from_utf8(ffmpeg_output.stderr).unwrap();
+            let score = metric
+                .score(output.as_str(), metric_options)
+                .unwrap_or_else(|| panic!("Failed to read {} score: {}", metric.name(), output));
+            (
+                score,
+                planes.map(|planes| check_planes(scene_number, cq, planes)),
+                cpu,
+            )
+        }
+        None => {
+            let mut decode = ffmpeg
+                .stdout(Stdio::piped())
+                .arg("-pix_fmt")
+                .arg(pix_fmt)
+                .arg("-strict")
+                .arg("-1")
+                .arg("-f")
+                .arg("yuv4mpegpipe")
+                .arg("-")
+                .spawn()
+                .unwrap();
+            match metric.scorer(metric_options) {
+                Some(mut scorer) => {
+                    // The decode only passes through here when its planes are wanted, otherwise it goes straight
+                    // to the scorer
+                    let scorer_input: Stdio = if wants_planes {
+                        Stdio::piped()
+                    } else {
+                        decode
+                            .stdout
+                            .take()
+                            .unwrap()
+                            .try_into()
+                            .expect("failed to convert to Stdio")
+                    };
+                    let mut scorer = scorer
+                        .stdin(scorer_input)
+                        .stdout(Stdio::piped())
+                        .stderr(Stdio::null())
+                        .spawn()
+                        .unwrap();
+                    let decoded = decode.stdout.take();
+                    let scorer_stdin = scorer.stdin.take();
+                    let (decoded_result, scorer_output, planes) = join!(
+                        wait_with_cpu(&mut decode),
+                        wait_with_output_and_cpu(scorer),
+                        async {
+                            match (decoded, scorer_stdin) {
+                                (Some(decoded), Some(scorer_stdin)) => {
+                                    Some(compare_planes(&paths.input, decoded, scorer_stdin).await)
+                                }
+                                _ => None,
+                            }
+                        }
+                    );
+                    let (decode_status, decode_cpu) = decoded_result.unwrap();
+                    check_probe(scene_number, cq, decode_status);
+                    let (scorer_output, scorer_cpu) = scorer_output.unwrap();
+                    let output = String::from_utf8(scorer_output.stdout).unwrap();
+                    let score = metric
+                        .score(output.as_str(), metric_options)
+                        .unwrap_or_else(|| {
+                            panic!("Failed to read {} score: {}", metric.name(), output)
+                        });
+                    (
+                        score,
+                        planes.map(|planes| check_planes(scene_number, cq, planes)),
+                        decode_cpu + scorer_cpu,
+                    )
+                }
+                None => {
+                    let decoded = decode.stdout.take().unwrap();
+                    let (decoded_result, planes) = join!(
+                        wait_with_cpu(&mut decode),
+                        compare_planes(&paths.input, decoded, tokio::io::sink())
+                    );
+                    let (decode_status, cpu) = decoded_result.unwrap();
+                    check_probe(scene_number, cq, decode_status);
+                    let planes = check_planes(scene_number, cq, planes);
+                    println!(
+                        "{} at {}: PSNR Y {:.2} U {:.2} V {:.2}",
+                        scene_number,
+                        cq,
+                        planes.psnr(Y),
+                        planes.psnr(U),
+                        planes.psnr(V)
+                    );
+                    let score = metric.score_planes(&planes).unwrap_or_else(|| {
+                        panic!("{} has no way of scoring the scene", metric.name())
+                    });
+                    (score, Some(planes), cpu)
+                }
+            }
+        }
+    };
+
+    if Path::new(&metric_log).exists() {
+        remove_file(&metric_log).await.unwrap();
+    }
+    (score / 100.0, planes, cpu)
+}
+
+/// Compares the decoded frames of a probe against the scene's source, passing them on to `forward` as they go.
+async fn compare_planes(
+    reference: &str,
+    decoded: ChildStdout,
+    mut forward: impl AsyncWriteExt + Unpin,
+) -> std::io::Result<PlaneScores> {
+    let mut reference = BufReader::new(File::open(reference).await?);
+    plane_metric::compare(&mut reference, &mut BufReader::new(decoded), &mut forward).await
+}
+
+fn check_planes(scene_number: u32, cq: u32, planes: std::io::Result<PlaneScores>) -> PlaneScores {
+    planes.unwrap_or_else(|err| {
+        panic!(
+            "Failed to compare scene {} at {} against its source: {}",
+            scene_number, cq, err
+        )
+    })
+}
+
+fn check_probe(scene_number: u32, cq: u32, status: ExitStatus) {
+    assert!(
+        status.success(),
+        "Probe of scene {} at {} failed: {}",
+        scene_number,
+        cq,
+        status
+    );
+}
+
+/// Runs a probe encode of the scene, returning the size of the bitstream in bytes and the encoder's CPU time.
+async fn size_second_pass(
+    scene_number: u32,
+    cq: u32,
+    cpu_used: u32,
+    context: Arc<FileContext>,
+) -> (u64, Duration) {
+    let paths = context.scene_paths(scene_number);
+    if let Some(kept) = context.probe_output(scene_number, cq, cpu_used) {
+        let mut encode = context
+            .encoder
+            .probe(EncoderOptions {
+                cq,
+                cpu_used,
+                output: &kept,
+                ..context.options(&paths)
+            })
+            .spawn()
+            .unwrap();
+        let (encode_status, cpu) = wait_with_cpu(&mut encode).await.unwrap();
+        check_probe(scene_number, cq, encode_status);
+        return (metadata(&kept).await.unwrap().len(), cpu);
+    }
+    let mut encode = context
+        .encoder
+        .probe(EncoderOptions {
+            cq,
+            cpu_used,
+            output: "-",
+            ..context.options(&paths)
+        })
+        .stdout(Stdio::piped())
         .spawn()
         .unwrap();
+    let mut bitstream = encode.stdout.take().unwrap();
+    let mut sink = tokio::io::sink();
+    let (size, encode_result) = join!(
+        tokio::io::copy(&mut bitstream, &mut sink),
+        wait_with_cpu(&mut encode)
+    );
+    let (encode_status, cpu) = encode_result.unwrap();
+    check_probe(scene_number, cq, encode_status);
+    (size.unwrap(), cpu)
+}
+
+/// How far a score out of 1 is past the quality `target`. With a chroma floor the scene is only as good as the worse
+/// of its quality and its chroma.
+fn quality_distance(
+    scene_number: u32,
+    cq: u32,
+    score: f64,
+    chroma: Option<f64>,
+    target: f64,
+    settings: &Settings,
+) -> f64 {
+    let distance = score - target;
+    match (settings.chroma_floor, chroma) {
+        (Some(floor), Some(chroma)) => {
+            let chroma_distance = floor.distance(chroma);
+            if chroma_distance < distance {
+                println!(
+                    "{} at {}: chroma {:.2} is under the floor",
+                    scene_number, cq, chroma
+                );
+            }
+            distance.min(chroma_distance)
+        }
+        _ => distance,
+    }
+}
 
-    let (_, ffmpeg_output) = join!(encode.wait(), ffmpeg.wait_with_output());
+/// What the search is trying to hit for a scene.
+#[derive(Copy, Clone)]
+enum SceneTarget {
+    Quality(f64),
+    Bits(f64),
+}
 
-    lazy_static! {
-        static ref VMAF_RE: Regex = Regex::new(r"VMAF score:\s+([\d|.]+)").unwrap();
+impl SceneTarget {
+    /// Probes the scene at `cq`, returning how far off the target it is along with a record of the probe. Probes
+    /// meeting the target come out positive, so the search can treat both kinds of target the same way.
+    async fn probe(
+        &self,
+        scene_number: u32,
+        scene_hash: SceneHash,
+        cq: u32,
+        cpu_used: u32,
+        context: Arc<FileContext>,
+    ) -> (f64, ProbeRecord) {
+        let start = Instant::now();
+        // The cache holds what the probe measured rather than the distance, so a different target can use it too
+        let sampled = context.sampled(scene_number);
+        let key = self.probe_key(scene_hash, cq, cpu_used, sampled, &context);
+        let cache = context.settings.probe_cache.as_ref();
+        let cached = match cache {
+            Some(cache) => cache.get(&key).await,
+            None => None,
+        };
+        let (measured, cpu) = match cached {
+            Some(measured) => {
+                println!("{} at {}: from the probe cache", scene_number, cq);
+                (measured, None)
+            }
+            None => {
+                let (measured, cpu) = match self {
+                    SceneTarget::Quality(_) => {
+                        let (score, planes, cpu) =
+                            quality_second_pass(scene_number, cq, cpu_used, context.clone()).await;
+                        let chroma = context
+                            .settings
+                            .chroma_floor
+                            .zip(planes)
+                            .map(|(floor, planes)| floor.score(&planes));
+                        (json!({"score": score, "chroma": chroma}), cpu)
+                    }
+                    SceneTarget::Bits(_) => {
+                        let (bytes, cpu) =
+                            size_second_pass(scene_number, cq, cpu_used, context.clone()).await;
+                        (json!({ "bits": bytes * 8 }), cpu)
+                    }
+                };
+                if let Some(cache) = cache {
+                    cache.insert(key, measured.clone()).await;
+                }
+                (measured, Some(cpu))
+            }
+        };
+        let record = ProbeRecord {
+            cq,
+            cpu_used,
+            score: match self {
+                SceneTarget::Quality(_) => measured["score"].as_f64().unwrap() * 100.0,
+                SceneTarget::Bits(_) => measured["bits"].as_f64().unwrap(),
+            },
+            chroma: measured["chroma"].as_f64(),
+            sampled,
+            cached: cpu.is_none(),
+            wall: start.elapsed(),
+            cpu: cpu.unwrap_or_default(),
+        };
+        let distance = match self {
+            // With a chroma floor the probe is only as good as the worse of the quality and the chroma
+            SceneTarget::Quality(target) => quality_distance(
+                scene_number,
+                cq,
+                measured["score"].as_f64().unwrap(),
+                measured["chroma"].as_f64(),
+                *target,
+                &context.settings,
+            ),
+            // Sizes are compared as a log ratio so being 10% over budget is as far off for small scenes as big ones
+            SceneTarget::Bits(target) => {
+                let bits = measured["bits"].as_f64().unwrap();
+                target.ln() - bits.max(1.0).ln()
+            }
+        };
+        (distance, record)
     }
-    let results = String::from_utf8(ffmpeg_output.unwrap().stderr).unwrap();
-    let captures: Captures = VMAF_RE
-        .captures(results.as_str())
-        .expect(format!("Failed to decode: {}", results).as_str());
-    let capture: &str = &captures[1];
 
-    capture.parse().map(|n: f64| n / 100.0).unwrap()
+    /// Everything that goes into a probe's result. The probe command is built with placeholder paths, so the key
+    /// doesn't depend on the temp folder or the scene's number.
+    fn probe_key(
+        &self,
+        scene_hash: SceneHash,
+        cq: u32,
+        cpu_used: u32,
+        sampled: bool,
+        context: &FileContext,
+    ) -> String {
+        let paths = ScenePaths {
+            base: "scene".to_string(),
+            log_file: "scene.log".to_string(),
+            input: "scene.y4m".to_string(),
+            output: "-".to_string(),
+            grain_table: "scene.tbl".to_string(),
+        };
+        let options = EncoderOptions {
+            cq,
+            cpu_used,
+            ..context.options(&paths)
+        };
+        let probe = context.encoder.probe(options);
+        let mut key = format!("{} {}", scene_hash.hex(), command_line(&probe));
+        // Side data like a photon noise table changes the encode without showing up in the probe's own command
+        if let Some(prepare) = context.encoder.prepare_scene(options) {
+            key = format!("{} | {}", key, command_line(&prepare));
+        }
+        key = format!("{} | {}", key, context.settings.tool_versions);
+        if sampled {
+            key = format!("{} | {:?}", key, context.settings.sampling.unwrap());
+        }
+        match self {
+            SceneTarget::Quality(_) => format!(
+                "{} | {:?} {:?}",
+                key,
+                context.settings.metric,
+                context.settings.chroma_floor.map(|floor| floor.metric)
+            ),
+            SceneTarget::Bits(_) => format!("{} | bits", key),
+        }
+    }
+
+    /// The measurement `probe` came back with, for printing.
+    fn value(&self, distance: f64) -> f64 {
+        match self {
+            SceneTarget::Quality(target) => target + distance,
+            SceneTarget::Bits(target) => target / distance.exp(),
+        }
+    }
+
+    /// The ends of the quality range probes come out furthest past and furthest short of the target.
+    fn ends(&self, parameter: &QualityParameter) -> (u32, u32) {
+        match self {
+            SceneTarget::Quality(_) => (parameter.best(), parameter.worst()),
+            SceneTarget::Bits(_) => (parameter.worst(), parameter.best()),
+        }
+    }
 }
 
-async fn vmaf_secant_search(
-    min: u32,
-    max: u32,
+async fn search_cq(
     initial_guess_min: u32,
     initial_guess_max: u32,
-    vmaf_cpu_used: u32,
-    target: f64,
+    target: SceneTarget,
     scene_number: u32,
-    tmp_folder: String,
-    encoder: Arc<dyn Encoder + Send + Sync>,
-) -> u32 {
-    let mut x1 = initial_guess_min;
-    let mut x2 = initial_guess_max;
-    let f = tmp_folder.clone();
-    let f2 = tmp_folder.clone();
-    let e1 = encoder.clone();
-    let e2 = encoder.clone();
-    let first_fx1 =
-        task::spawn(async move { vmaf_second_pass(scene_number, x1, 6, 1, f.clone(), e1).await });
-    let first_fx2 =
-        task::spawn(async move { vmaf_second_pass(scene_number, x2, 6, 1, f2.clone(), e2).await });
-    let (fx1_result, fx2_result) = join!(first_fx1, first_fx2);
-    let fx1_target = fx1_result.unwrap() - target;
-    let mut fx1 = fx1_target;
-    let mut fx2 = fx2_result.unwrap() - target;
-    // If vmaf for the second value is greater then the target, then we want it pinned so future guess aren't lower than this first high guess.
-    // For example, if the guess is 40 with vmaf of 99 and a target of 95, a guess of 60 might return 80, which would make the next guess less than 40 (since it falls off naturally as a result of secant searching)
-    // This swap keeps the 40 for the next pass which will make the next guess > 40.
-    // This only applies to the first pass as subsequent guesses should always narrow into the right result.
-    if fx2.abs() < fx1.abs() {
-        fx1 = fx2;
-        x1 = x2;
-        fx2 = fx1_target;
-        x2 = initial_guess_min;
-    }
-    let mut iterations = 0;
-    while fx1.abs() > 0.005 && iterations < 10 {
-        let mut next = (x1 as f64 - (fx1 * ((x1 as f64 - x2 as f64) / (fx1 - fx2)))).floor() as u32;
+    scene_hash: SceneHash,
+    context: Arc<FileContext>,
+) -> (u32, Vec<ProbeRecord>, bool) {
+    let parameter = context.encoder.quality_parameter();
+    let options = context.settings.search;
+    let (pass_end, fail_end) = target.ends(&parameter);
+    let mut search = Search::new(options, pass_end, fail_end);
+    let c1 = context.clone();
+    let c2 = context.clone();
+    let first_fx1 = task::spawn(async move {
+        target
+            .probe(scene_number, scene_hash, initial_guess_min, 6, c1)
+            .await
+    });
+    // The same guess twice would only probe it twice over, into the same kept bitstream too
+    let first_fx2 = (initial_guess_max != initial_guess_min).then(|| {
+        task::spawn(async move {
+            target
+                .probe(scene_number, scene_hash, initial_guess_max, 6, c2)
+                .await
+        })
+    });
+    let (fx1_result, fx2_result) = join!(first_fx1, async {
+        match first_fx2 {
+            Some(first_fx2) => Some(first_fx2.await),
+            None => None,
+        }
+    });
+    let mut first = vec![fx1_result.unwrap()];
+    first.extend(fx2_result.map(|result| result.unwrap()));
+    // The secant follows the last two probes, so add the one closer to the target last. Otherwise a guess of 40 with
+    // vmaf of 99 against a target of 95 would be followed from 60 coming back at 80, sending the next guess below 40.
+    first.sort_by(|(a, _), (b, _)| b.abs().partial_cmp(&a.abs()).unwrap());
+    let mut probes = vec![];
+    for (distance, record) in first {
         println!(
-            "{}({}): {}:{} {}:{} → {}",
+            "{}: {} {}:{}",
             scene_number,
-            iterations,
-            x1,
-            fx1 + target,
-            x2,
-            fx2 + target,
-            next
+            parameter.name,
+            record.cq,
+            target.value(distance)
         );
-        x2 = x1;
-        fx2 = fx1;
-        if next < min {
-            if x1 == min {
-                // We already tried min.  No reason to try again.
-                break;
-            } else {
-                next = min
-            }
-        } else if next > max {
-            if x1 == max {
-                // We already tried max, don't try it again.
-                break;
-            } else {
-                next = max
-            }
-        } else if next == x1 {
-            // We are so close that the next guess ends up being the current guess, just jump out
-            break;
-        }
-        if x1 == max && fx1 > 0.0 {
-            break;
-        }
-        x1 = next;
-        fx1 = vmaf_second_pass(
+        search.add(record.cq, distance);
+        probes.push(record);
+    }
+    for iteration in 0..options.max_iterations {
+        let next = match search.next() {
+            Some(next) => next,
+            None => break,
+        };
+        let (distance, record) = target
+            .probe(
+                scene_number,
+                scene_hash,
+                next,
+                context.settings.vmaf_cpu_used,
+                context.clone(),
+            )
+            .await;
+        println!(
+            "{}({}): {} {}:{}",
             scene_number,
-            x1,
-            vmaf_cpu_used,
-            2,
-            tmp_folder.clone(),
-            encoder.clone(),
-        )
-        .await
-            - target;
-        iterations += 1;
+            iteration,
+            parameter.name,
+            next,
+            target.value(distance)
+        );
+        search.add(next, distance);
+        probes.push(record);
     }
-    println!("{}: {}:{}", scene_number, x1, fx1 + target);
-    if fx1 > 0.0 {
-        x1
+    let answer = search.answer();
+    let met_target = search.met_target();
+    if met_target {
+        println!("{}: {} {}", scene_number, parameter.name, answer);
     } else {
-        (x1 - 1).max(min)
+        println!(
+            "{}: warning, no probe met the target, settling for {} {}",
+            scene_number, parameter.name, answer
+        );
     }
+    (answer, probes, !met_target)
 }
 
 fn stats_processor(
@@ -658,12 +1895,14 @@ fn stats_processor(
                 stats_tx.send(FrameStats {
                     frame_num: current.frame as u64,
                     is_keyframe: true,
+                    complexity: current.complexity(),
                 });
                 since_last_keyframe = 0;
             } else {
                 stats_tx.send(FrameStats {
                     frame_num: current.frame as u64,
                     is_keyframe: false,
+                    complexity: current.complexity(),
                 });
             }
             since_last_keyframe += 1;
@@ -677,11 +1916,13 @@ fn stats_processor(
                         stats_tx.send(FrameStats {
                             frame_num: current.frame as u64,
                             is_keyframe: false,
+                            complexity: current.complexity(),
                         });
                         for stats in frame_stats {
                             stats_tx.send(FrameStats {
                                 frame_num: stats.frame as u64,
                                 is_keyframe: false,
+                                complexity: stats.complexity(),
                             });
                         }
                         drop(stats_tx);
@@ -697,11 +1938,13 @@ fn stats_processor(
 
 fn aom_firstpass_for_scene_detection(
     video_header: VideoHeader,
+    bit_depth: u32,
     analyzed_aom_frames: Arc<Semaphore>,
     writing_buf: Arc<FrameBuffer>,
     tmp_folder: String,
 ) -> JoinHandle<()> {
-    let mut aom = start_aom_scene_detection(tmp_folder.clone());
+    let mut aom =
+        start_aom_scene_detection(tmp_folder.clone(), bit_depth, video_header.bit_depth());
     let mut aom_input = aom.stdin.take().unwrap();
     task::spawn(async move {
         video_header.clone().write(&mut aom_input).await.unwrap();
@@ -726,19 +1969,30 @@ fn aom_firstpass_for_scene_detection(
     })
 }
 
-fn start_aom_scene_detection(tmp_folder: String) -> Child {
+fn aom_scene_detection_args(tmp_folder: &str, bit_depth: u32, input_bit_depth: u32) -> Vec<String> {
+    vec![
+        "--passes=2".to_string(),
+        "--pass=1".to_string(),
+        format!("--bit-depth={}", bit_depth),
+        format!("--input-bit-depth={}", input_bit_depth),
+        format!("--fpf={}/keyframe.log", tmp_folder),
+        "--end-usage=q".to_string(),
+        "--threads=4".to_string(),
+        "-o".to_string(),
+        "/dev/null".to_string(),
+        "-".to_string(),
+    ]
+}
+
+fn start_aom_scene_detection(tmp_folder: String, bit_depth: u32, input_bit_depth: u32) -> Child {
     Command::new("nice")
         .arg("-20")
         .arg("aomenc")
-        .arg("--passes=2")
-        .arg("--pass=1")
-        .arg("--bit-depth=10")
-        .arg(format!("--fpf={}/keyframe.log", tmp_folder))
-        .arg("--end-usage=q")
-        .arg("--threads=4")
-        .arg("-o")
-        .arg("/dev/null")
-        .arg("-")
+        .args(aom_scene_detection_args(
+            tmp_folder.as_str(),
+            bit_depth,
+            input_bit_depth,
+        ))
         .stdin(Stdio::piped())
         .spawn()
         .unwrap()
@@ -763,6 +2017,34 @@ fn start_vspipe(input: &str, vpy: &str, tmp_folder: String) -> Child {
         .unwrap()
 }
 
+/// Strongest denoising allowed with `--film_grain`. The probes score the denoised encode against the noisy source, so
+/// the stronger the denoising the more a scene's score undersells it and the more bits the search spends on it.
+const MAX_DENOISE_LEVEL: u32 = 50;
+
+fn film_grain(options: &ArgMatches) -> FilmGrain {
+    if options.is_present("film_grain") {
+        let level = options.value_of_t_or_exit("film_grain");
+        if !(1..=MAX_DENOISE_LEVEL).contains(&level) {
+            panic!(
+                "Film grain denoise level has to be between 1 and {}",
+                MAX_DENOISE_LEVEL
+            );
+        }
+        FilmGrain::Denoise(level)
+    } else if options.is_present("photon_noise") {
+        FilmGrain::Photon(options.value_of_t_or_exit("photon_noise"))
+    } else {
+        FilmGrain::Off
+    }
+}
+
+fn split_args(options: &ArgMatches, name: &str) -> Vec<String> {
+    options
+        .value_of(name)
+        .map(|args| args.split_whitespace().map(|a| a.to_string()).collect())
+        .unwrap_or_default()
+}
+
 fn extract_options() -> ArgMatches {
     App::new("sav1n")
         .version("0.0.1")
@@ -798,10 +2080,159 @@ fn extract_options() -> ArgMatches {
             Arg::new("vmaf_target")
                 .short('t')
                 .long("vmaf_target")
-                .default_value("95")
+                .alias("target")
+                .help("Quality each scene has to reach, on the scale of the chosen metric. Defaults to 95 for vmaf, the other metrics need one")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("metric")
+                .long("metric")
+                .help("Metric the quality target is measured with, ssimulacra2 needs ssimulacra2_rs installed and the native ones are computed without ffmpeg filters")
+                .default_value("vmaf")
+                .possible_values(["vmaf", "ssimulacra2", "xpsnr", "psnr", "native_psnr", "native_ssim"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("pooling")
+                .long("pooling")
+                .help("vmaf only: how frame scores make up a scene's score, mean, harmonic or a percentile like p5")
+                .default_value("mean")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("vmaf_model")
+                .long("vmaf_model")
+                .help("vmaf model file or built in model name, defaults to vmaf_v0.6.1 or vmaf_4k_v0.6.1 for 2160p and up")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("vmaf_neg")
+                .long("vmaf_neg")
+                .help("Pick the NEG version of the default vmaf model, which doesn't reward sharpening")
+                .conflicts_with("vmaf_model")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("chroma_floor")
+                .long("chroma_floor")
+                .help("Least the worse of the U and V planes may score on top of meeting vmaf_target, in dB for psnr or 0-100 for ssim")
+                .conflicts_with_all(&["target_bitrate", "target_size", "cq", "lossless"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("chroma_metric")
+                .long("chroma_metric")
+                .help("How chroma_floor is measured")
+                .default_value("psnr")
+                .possible_values(["psnr", "ssim"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("search")
+                .long("search")
+                .help("How each scene's quality parameter is searched for")
+                .default_value("secant")
+                .possible_values(["secant", "bisection", "illinois", "model"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("search_tolerance")
+                .long("search_tolerance")
+                .help("How far past the target a probe may land and still end the search, with vmaf targets out of 1")
+                .default_value("0.005")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("search_iterations")
+                .long("search_iterations")
+                .help("Most probes to do per scene after the first two")
+                .default_value("10")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("probe_cache")
+                .long("probe_cache")
+                .help("JSON file to keep probe results in, so re-runs of unchanged scenes can skip them")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("keep_probes")
+                .long("keep_probes")
+                .help("Keep the bitstream of probes at cpu_used, so a scene settling on one skips its second pass")
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("sample_clips")
+                .long("sample_clips")
+                .help("Probe scenes at least twice as long as the clips on this many evenly spaced clips out of them, needs --verify to correct for what the clips missed")
+                .conflicts_with_all(&["target_bitrate", "target_size", "cq", "lossless"])
+                .requires("verify")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("sample_frames")
+                .long("sample_frames")
+                .help("Frames in each clip of a sampled scene")
+                .default_value("24")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("verify")
+                .long("verify")
+                .help("Score every scene's final encode, re-encoding scenes that miss vmaf_target by more than verify_tolerance")
+                .conflicts_with_all(&["target_bitrate", "target_size", "cq", "lossless"])
+                .takes_value(false),
+        )
+        .arg(
+            Arg::new("verify_tolerance")
+                .long("verify_tolerance")
+                .help("How far under vmaf_target a verified scene may score, on the scale of the chosen metric")
+                .default_value("1")
                 .multiple_values(false)
                 .takes_value(true),
         )
+        .arg(
+            Arg::new("target_bitrate")
+                .long("target_bitrate")
+                .help("Average video bitrate in kbps to spread over the scenes, replaces vmaf_target")
+                .conflicts_with("target_size")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("target_size")
+                .long("target_size")
+                .help("Total size in MB for the video of all inputs together, replaces vmaf_target")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("cq")
+                .long("cq")
+                .help("Encode every scene at this value of the encoder's quality parameter instead of searching for one")
+                .conflicts_with_all(&["target_bitrate", "target_size", "lossless"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("lossless")
+                .long("lossless")
+                .help("Encode every scene losslessly, for archiving the vapoursynth output")
+                .conflicts_with_all(&["target_bitrate", "target_size", "film_grain", "photon_noise"])
+                .takes_value(false),
+        )
         .arg(
             Arg::new("cpu_used")
                 .short('c')
@@ -822,7 +2253,65 @@ fn extract_options() -> ArgMatches {
                 .short('o')
                 .long("codec")
                 .default_value("vpx")
-                .possible_values(["vpx", "av1"])
+                .possible_values(["vpx", "av1", "svt", "rav1e", "x265", "x264"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("bit_depth")
+                .long("bit_depth")
+                .help("Bit depth to encode at, defaults to the bit depth of the vapoursynth output, or 8 for x264 when not lossless. svt and rav1e only encode at the bit depth of the vapoursynth output")
+                .possible_values(["8", "10"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("encoder_config")
+                .long("encoder_config")
+                .help("JSON file with command templates for a custom encoder, overrides codec")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("film_grain")
+                .long("film_grain")
+                .help("av1 only: denoise level (1-50) for aomenc to estimate each scene's grain from. The vmaf target is scored on the denoised encode against the noisy source, so expect larger encodes for the same target")
+                .conflicts_with_all(&["photon_noise", "encoder_config"])
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("photon_noise")
+                .long("photon_noise")
+                .help("av1 only: ISO strength of the photon noise grain table to synthesize")
+                .conflicts_with("encoder_config")
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("first_pass_args")
+                .long("first_pass_args")
+                .alias("first-pass-args")
+                .help("Extra encoder arguments for the first pass, replacing any defaults they conflict with")
+                .allow_hyphen_values(true)
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("second_pass_args")
+                .long("second_pass_args")
+                .alias("second-pass-args")
+                .help("Extra encoder arguments for the second pass and the vmaf probes, replacing any defaults they conflict with")
+                .allow_hyphen_values(true)
+                .multiple_values(false)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::new("probe_args")
+                .long("probe_args")
+                .alias("probe-args")
+                .help("Extra encoder arguments only used by the vmaf probes, applied after second_pass_args")
+                .allow_hyphen_values(true)
                 .multiple_values(false)
                 .takes_value(true),
         )
@@ -851,4 +2340,97 @@ fn align_power_of_two(value: i32, n: i32) -> i32 {
 struct FrameStats {
     frame_num: u64,
     is_keyframe: bool,
+    complexity: f64,
+}
+
+/// First pass totals for the frames of a scene.
+#[derive(Copy, Clone, Default)]
+struct SceneStats {
+    start_frame: u64,
+    frames: u64,
+    complexity: f64,
+    /// Hash of the scene's y4m, which identifies it in the probe cache.
+    hash: SceneHash,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::Encoder;
+    use crate::quality_metric::{Pooling, Vmaf};
+    use crate::search::{SearchOptions, Strategy};
+    use crate::svt_av1_encoder::SvtAv1Encoder;
+    use crate::threading::Threading;
+    use crate::video_header::VideoHeader;
+    use crate::{FileContext, Settings};
+    use std::sync::Arc;
+    use tokio::sync::Mutex;
+
+    fn context(tmp_folder: &str, encoder: Arc<dyn Encoder + Send + Sync>) -> FileContext {
+        FileContext {
+            settings: Arc::new(Settings {
+                cpu_used: 4,
+                vmaf_cpu_used: 6,
+                vmaf_target: 0.95,
+                metric: Box::new(Vmaf {
+                    pooling: Pooling::Mean,
+                    model: None,
+                    neg: false,
+                }),
+                chroma_floor: None,
+                search: SearchOptions {
+                    strategy: Strategy::Secant,
+                    tolerance: 0.5,
+                    max_iterations: 4,
+                },
+                probe_cache: None,
+                tool_versions: String::new(),
+                keep_probes: true,
+                verify_tolerance: None,
+                sampling: None,
+                bitrate: None,
+                fixed_cq: None,
+                lossless: false,
+                bit_depth: None,
+                encoders: 1,
+            }),
+            tmp_folder: tmp_folder.to_string(),
+            header: VideoHeader {
+                width: 1920,
+                height: 1080,
+                ..VideoHeader::new()
+            },
+            bit_depth: 10,
+            threading: Threading {
+                threads: 4,
+                tile_columns_log2: 1,
+                tile_rows_log2: 0,
+            },
+            rate_control: None,
+            color: Default::default(),
+            encoder,
+            reports: Mutex::new(vec![]),
+        }
+    }
+
+    #[test]
+    fn probe_output() {
+        let tmp_folder =
+            std::env::temp_dir().join(format!("sav1n-probe-output-{}", std::process::id()));
+        std::fs::create_dir_all(&tmp_folder).unwrap();
+        let mut context = context(tmp_folder.to_str().unwrap(), Arc::new(SvtAv1Encoder {}));
+        // A probe at the final encode's speed is the final encode, one at another speed isn't
+        assert_eq!(
+            context.probe_output(3, 30, 4),
+            Some(format!("{}/000003.30.probe.ivf", tmp_folder.display()))
+        );
+        assert_eq!(context.probe_output(3, 30, 6), None);
+        // Probes of clips out of the scene never are
+        std::fs::write(context.sample_paths(3).input, b"").unwrap();
+        assert_eq!(context.probe_output(3, 30, 4), None);
+        std::fs::remove_file(context.sample_paths(3).input).unwrap();
+        // Nor is any probe kept without asking for it
+        Arc::get_mut(&mut context.settings).unwrap().keep_probes = false;
+        assert_eq!(context.probe_output(3, 30, 4), None);
+        std::fs::remove_dir_all(tmp_folder).unwrap();
+    }
 }
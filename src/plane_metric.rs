@@ -0,0 +1,397 @@
+use crate::frame::{Frame, Status};
+use crate::video_header::VideoHeader;
+use std::io::ErrorKind;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt, Error};
+
+pub const Y: usize = 0;
+pub const U: usize = 1;
+pub const V: usize = 2;
+
+/// Identical planes have an infinite PSNR, which would throw the search off.
+const MAX_PSNR: f64 = 100.0;
+/// SSIM is worked out over 8x8 windows, each overlapping the last by half.
+const SSIM_WINDOW: usize = 8;
+const SSIM_STEP: usize = 4;
+
+/// PSNR and SSIM of every plane, built up one frame at a time by comparing the decoded encode against its source.
+#[derive(Clone, Debug)]
+pub struct PlaneScores {
+    bit_depth: u32,
+    squared_error: [f64; 3],
+    samples: [u64; 3],
+    ssim: [f64; 3],
+    windows: [u64; 3],
+    pub frames: u64,
+}
+
+impl PlaneScores {
+    pub fn new(bit_depth: u32) -> PlaneScores {
+        PlaneScores {
+            bit_depth,
+            squared_error: [0.0; 3],
+            samples: [0; 3],
+            ssim: [0.0; 3],
+            windows: [0; 3],
+            frames: 0,
+        }
+    }
+
+    /// Compares a frame of the encode against the same frame of the source, both laid out as `header` describes.
+    pub fn add_frame(&mut self, header: &VideoHeader, reference: &[u8], distorted: &[u8]) {
+        let bytes = header.bytes_per_sample();
+        let mut offset = 0;
+        for (plane, (width, height)) in header.plane_sizes().into_iter().enumerate() {
+            let len = width * height * bytes;
+            let reference = samples(&reference[offset..offset + len], bytes);
+            let distorted = samples(&distorted[offset..offset + len], bytes);
+            offset += len;
+
+            self.squared_error[plane] += reference
+                .iter()
+                .zip(&distorted)
+                .map(|(r, d)| (r - d) * (r - d))
+                .sum::<f64>();
+            self.samples[plane] += reference.len() as u64;
+            let (ssim, windows) = self.plane_ssim(&reference, &distorted, width, height);
+            self.ssim[plane] += ssim;
+            self.windows[plane] += windows;
+        }
+        self.frames += 1;
+    }
+
+    /// PSNR of a single plane over every frame seen.
+    pub fn psnr(&self, plane: usize) -> f64 {
+        self.to_psnr(self.squared_error[plane], self.samples[plane])
+    }
+
+    /// PSNR over the samples of all planes, the way ffmpeg's `average` does it.
+    pub fn psnr_all(&self) -> f64 {
+        self.to_psnr(self.squared_error.iter().sum(), self.samples.iter().sum())
+    }
+
+    /// Mean SSIM of a single plane over every frame seen, from 0 to 1.
+    pub fn ssim(&self, plane: usize) -> f64 {
+        self.ssim[plane] / self.windows[plane].max(1) as f64
+    }
+
+    /// SSIM of all planes, weighted by how many samples they have.
+    pub fn ssim_all(&self) -> f64 {
+        let samples: u64 = self.samples.iter().sum();
+        (0..3)
+            .map(|plane| self.ssim(plane) * self.samples[plane] as f64)
+            .sum::<f64>()
+            / samples.max(1) as f64
+    }
+
+    fn peak(&self) -> f64 {
+        ((1u32 << self.bit_depth) - 1) as f64
+    }
+
+    fn to_psnr(&self, squared_error: f64, samples: u64) -> f64 {
+        if squared_error == 0.0 || samples == 0 {
+            return MAX_PSNR;
+        }
+        let mse = squared_error / samples as f64;
+        (10.0 * (self.peak() * self.peak() / mse).log10()).min(MAX_PSNR)
+    }
+
+    /// Sums the SSIM of every window of the plane, returning the sum and how many windows there were.
+    fn plane_ssim(
+        &self,
+        reference: &[f64],
+        distorted: &[f64],
+        width: usize,
+        height: usize,
+    ) -> (f64, u64) {
+        let c1 = (0.01 * self.peak()).powi(2);
+        let c2 = (0.03 * self.peak()).powi(2);
+        // Planes smaller than a window are treated as a single window
+        let window_width = width.min(SSIM_WINDOW);
+        let window_height = height.min(SSIM_WINDOW);
+        let count = (window_width * window_height) as f64;
+        let mut sum = 0.0;
+        let mut windows = 0;
+        for top in (0..=height - window_height).step_by(SSIM_STEP) {
+            for left in (0..=width - window_width).step_by(SSIM_STEP) {
+                let (mut r_sum, mut d_sum, mut rr, mut dd, mut rd) = (0.0, 0.0, 0.0, 0.0, 0.0);
+                for row in top..top + window_height {
+                    let start = row * width + left;
+                    for (r, d) in reference[start..start + window_width]
+                        .iter()
+                        .zip(&distorted[start..start + window_width])
+                    {
+                        r_sum += r;
+                        d_sum += d;
+                        rr += r * r;
+                        dd += d * d;
+                        rd += r * d;
+                    }
+                }
+                let r_mean = r_sum / count;
+                let d_mean = d_sum / count;
+                let r_variance = rr / count - r_mean * r_mean;
+                let d_variance = dd / count - d_mean * d_mean;
+                let covariance = rd / count - r_mean * d_mean;
+                sum += ((2.0 * r_mean * d_mean + c1) * (2.0 * covariance + c2))
+                    / ((r_mean * r_mean + d_mean * d_mean + c1) * (r_variance + d_variance + c2));
+                windows += 1;
+            }
+        }
+        (sum, windows)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ChromaMetric {
+    Psnr,
+    Ssim,
+}
+
+/// The least the U and V planes of a scene may score, since VMAF only looks at luma and happily passes encodes with
+/// smeared colours. PSNR floors are in dB, SSIM floors on a 0-100 scale.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ChromaFloor {
+    pub metric: ChromaMetric,
+    pub floor: f64,
+}
+
+impl ChromaFloor {
+    /// The score of the worse of the two chroma planes.
+    pub fn score(&self, scores: &PlaneScores) -> f64 {
+        match self.metric {
+            ChromaMetric::Psnr => scores.psnr(U).min(scores.psnr(V)),
+            ChromaMetric::Ssim => scores.ssim(U).min(scores.ssim(V)) * 100.0,
+        }
+    }
+
+    /// How far above the floor a chroma `score` is, scaled down by 100 like the quality target so the two can be
+    /// compared.
+    pub fn distance(&self, score: f64) -> f64 {
+        (score - self.floor) / 100.0
+    }
+}
+
+fn samples(plane: &[u8], bytes: usize) -> Vec<f64> {
+    if bytes == 2 {
+        plane
+            .chunks_exact(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]) as f64)
+            .collect()
+    } else {
+        plane.iter().map(|&sample| sample as f64).collect()
+    }
+}
+
+/// Bytes in a frame laid out as `header` describes.
+fn frame_size(header: &VideoHeader) -> usize {
+    header
+        .plane_sizes()
+        .iter()
+        .map(|(width, height)| width * height)
+        .sum::<usize>()
+        * header.bytes_per_sample()
+}
+
+/// Reads two y4m streams to the end, comparing them frame by frame. Both need the same size and pixel format, so
+/// the encode should be decoded to the source's format. The encode's frames are passed on to `forward` as they are
+/// read, so a scorer can be fed from the same decode.
+pub async fn compare(
+    reference: &mut (impl AsyncBufReadExt + Unpin),
+    distorted: &mut (impl AsyncBufReadExt + Unpin),
+    forward: &mut (impl AsyncWriteExt + Unpin),
+) -> io::Result<PlaneScores> {
+    let header = VideoHeader::read(reference).await?;
+    let distorted_header = VideoHeader::read(distorted).await?;
+    if header.width != distorted_header.width
+        || header.height != distorted_header.height
+        || header.bit_depth() != distorted_header.bit_depth()
+        || header.plane_sizes() != distorted_header.plane_sizes()
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "Encode doesn't have the same frame layout as its source",
+        ));
+    }
+    forward.write_all(&distorted_header.as_bytes).await?;
+    let size = frame_size(&header);
+    let mut reference_frame = Frame::new(size, 0);
+    let mut distorted_frame = Frame::new(size, 0);
+    let mut scores = PlaneScores::new(header.bit_depth());
+    loop {
+        let reference_status = reference_frame.read(scores.frames, reference).await?;
+        let distorted_status = distorted_frame.read(scores.frames, distorted).await?;
+        match (reference_status, distorted_status) {
+            (Status::Processing, Status::Processing) => {
+                distorted_frame.write(forward).await?;
+                scores.add_frame(&header, reference_frame.data(), distorted_frame.data())
+            }
+            (Status::Completed, Status::Completed) => {
+                forward.flush().await?;
+                return Ok(scores);
+            }
+            _ => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "Encode and source differ in length after {} frames",
+                        scores.frames
+                    ),
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::plane_metric::{compare, ChromaFloor, ChromaMetric, PlaneScores, U, V, Y};
+    use crate::video_header::{ColorSpaceType, VideoHeader};
+    use std::io::Cursor;
+
+    fn header(color_space_type: ColorSpaceType) -> VideoHeader {
+        VideoHeader {
+            width: 16,
+            height: 16,
+            color_space_type: Some(color_space_type),
+            ..VideoHeader::new()
+        }
+    }
+
+    /// A 16x16 4:2:0 frame with a gradient in every plane, 8 bit samples when `bytes` is 1 and 10 bit when it's 2.
+    fn gradient(bytes: usize, scale: u16) -> Vec<u8> {
+        (0..16 * 16 + 2 * 8 * 8)
+            .map(|i| (i % 200) as u16 * scale)
+            .flat_map(|sample| sample.to_le_bytes()[..bytes].to_vec())
+            .collect()
+    }
+
+    #[test]
+    fn identical_frames() {
+        let frame = gradient(1, 1);
+        let mut scores = PlaneScores::new(8);
+        scores.add_frame(&header(ColorSpaceType::C420), &frame, &frame);
+        assert_eq!(scores.psnr_all(), 100.0);
+        assert!((scores.ssim_all() - 1.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn psnr_per_plane() {
+        let reference = gradient(1, 1);
+        let mut distorted = reference.clone();
+        // Every V sample off by one, which at 8 bits is 10 * log10(255^2) dB
+        for sample in &mut distorted[16 * 16 + 8 * 8..] {
+            *sample += 1;
+        }
+        let mut scores = PlaneScores::new(8);
+        scores.add_frame(&header(ColorSpaceType::C420), &reference, &distorted);
+        assert_eq!(scores.psnr(Y), 100.0);
+        assert_eq!(scores.psnr(U), 100.0);
+        assert!((scores.psnr(V) - 48.1308).abs() < 1e-4);
+        // A sixth of the samples are off, so the whole frame is 10 * log10(6) dB better
+        assert!((scores.psnr_all() - 55.9123).abs() < 1e-4);
+        assert!(scores.ssim(V) < 1.0 && scores.ssim(V) > 0.99);
+    }
+
+    #[test]
+    fn ten_bit_samples() {
+        let reference = gradient(2, 4);
+        let mut distorted = gradient(2, 4);
+        // Off by 4 at 10 bits is as far off as 1 at 8 bits, give or take the slightly larger peak
+        for pair in distorted.chunks_exact_mut(2) {
+            let sample = u16::from_le_bytes([pair[0], pair[1]]) + 4;
+            pair.copy_from_slice(&sample.to_le_bytes());
+        }
+        let mut scores = PlaneScores::new(10);
+        scores.add_frame(&header(ColorSpaceType::C420p10), &reference, &distorted);
+        assert!((scores.psnr(Y) - 48.1563).abs() < 1e-4);
+    }
+
+    #[test]
+    fn structure_matters_to_ssim() {
+        let reference = gradient(1, 1);
+        // Flattening the luma plane to its mean keeps its brightness but loses all detail
+        let mut flat = reference.clone();
+        let mean = reference[..256].iter().map(|&s| s as u32).sum::<u32>() / 256;
+        flat[..256].fill(mean as u8);
+        let mut scores = PlaneScores::new(8);
+        scores.add_frame(&header(ColorSpaceType::C420), &reference, &flat);
+        assert!(scores.ssim(Y) < 0.5);
+        assert!((scores.ssim(U) - 1.0).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn compares_streams() {
+        let mut reference = b"YUV4MPEG2 W16 H16 F25:1 Ip A0:0 C420\x0A".to_vec();
+        let mut distorted = reference.clone();
+        for _ in 0..2 {
+            reference.extend(b"FRAME\x0A");
+            reference.extend(gradient(1, 1));
+            distorted.extend(b"FRAME\x0A");
+            distorted.extend(gradient(1, 1));
+        }
+        let mut forwarded = vec![];
+        let scores = compare(
+            &mut Cursor::new(reference.clone()),
+            &mut Cursor::new(distorted.clone()),
+            &mut forwarded,
+        )
+        .await
+        .expect("should succeed");
+        assert_eq!(scores.frames, 2);
+        assert_eq!(scores.psnr_all(), 100.0);
+        // The encode's frames come out of the comparison untouched
+        assert_eq!(forwarded, distorted);
+
+        let short = b"YUV4MPEG2 W16 H16 F25:1 Ip A0:0 C420\x0A".to_vec();
+        assert!(compare(
+            &mut Cursor::new(reference),
+            &mut Cursor::new(short),
+            &mut tokio::io::sink()
+        )
+        .await
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn compares_chroma_siting_variants() {
+        // ffmpeg writes 8 bit 4:2:0 with its chroma siting, along with an XYSCSS comment
+        let mut reference = b"YUV4MPEG2 W16 H16 F25:1 Ip A0:0 C420\x0A".to_vec();
+        let mut distorted = b"YUV4MPEG2 W16 H16 F25:1 Ip A0:0 C420jpeg XYSCSS=420JPEG\x0A".to_vec();
+        reference.extend(b"FRAME\x0A");
+        reference.extend(gradient(1, 1));
+        distorted.extend(b"FRAME\x0A");
+        distorted.extend(gradient(1, 1));
+        let scores = compare(
+            &mut Cursor::new(reference),
+            &mut Cursor::new(distorted),
+            &mut tokio::io::sink(),
+        )
+        .await
+        .expect("should succeed");
+        assert_eq!(scores.frames, 1);
+        assert_eq!(scores.psnr_all(), 100.0);
+    }
+
+    #[test]
+    fn chroma_floor() {
+        let reference = gradient(1, 1);
+        let mut distorted = reference.clone();
+        for sample in &mut distorted[16 * 16 + 8 * 8..] {
+            *sample += 1;
+        }
+        let mut scores = PlaneScores::new(8);
+        scores.add_frame(&header(ColorSpaceType::C420), &reference, &distorted);
+        let psnr = ChromaFloor {
+            metric: ChromaMetric::Psnr,
+            floor: 45.0,
+        };
+        // Only the worse plane counts
+        assert!((psnr.score(&scores) - 48.1308).abs() < 1e-4);
+        assert!(psnr.distance(psnr.score(&scores)) > 0.0);
+        let ssim = ChromaFloor {
+            metric: ChromaMetric::Ssim,
+            floor: 99.999,
+        };
+        assert!(ssim.distance(ssim.score(&scores)) < 0.0);
+    }
+}
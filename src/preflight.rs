@@ -0,0 +1,229 @@
+use crate::encoder::{first_version, flag_name, is_flag, Encoder};
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::collections::BTreeMap;
+use std::process::Stdio;
+use tokio::process::Command;
+
+/// A binary that passed its preflight check.
+pub struct BinaryInfo {
+    pub program: String,
+    pub version: String,
+}
+
+/// How to get a program to list the flags it supports and print its version.
+pub enum Tool<'t> {
+    Encoder(&'t (dyn Encoder + Send + Sync)),
+    /// Any other program, which prints its version with `--version`.
+    Other {
+        help_args: Vec<&'t str>,
+    },
+}
+
+impl Tool<'_> {
+    fn help_args(&self) -> Vec<&str> {
+        match self {
+            Tool::Encoder(encoder) => encoder.help_args(),
+            Tool::Other { help_args } => help_args.clone(),
+        }
+    }
+
+    fn version_args(&self) -> Vec<&str> {
+        match self {
+            Tool::Encoder(encoder) => encoder.version_args(),
+            Tool::Other { .. } => vec!["--version"],
+        }
+    }
+
+    fn parse_version(&self, output: &str) -> Option<String> {
+        match self {
+            Tool::Encoder(encoder) => encoder.parse_version(output),
+            Tool::Other { .. } => first_version(output),
+        }
+    }
+}
+
+/// Makes sure every program used by `commands` runs and understands every flag passed to it, asking each program
+/// for its help and version the way the tool it came with says to. The first tool given for a program is used.
+/// This turns a missing binary or an encoder build lacking a flag into an error up front, rather than scenes
+/// silently failing once the encode is well under way.
+pub async fn preflight(commands: Vec<(Command, Tool<'_>)>) -> Result<Vec<BinaryInfo>, String> {
+    let mut flags: BTreeMap<String, (Tool, Vec<String>)> = BTreeMap::new();
+    for (command, tool) in commands {
+        let command = command.as_std();
+        let program = command.get_program().to_str().unwrap().to_string();
+        let (_, program_flags) = flags.entry(program).or_insert((tool, vec![]));
+        for arg in command.get_args() {
+            let arg = arg.to_str().unwrap();
+            if is_flag(arg) && !program_flags.iter().any(|f| f == flag_name(arg)) {
+                program_flags.push(flag_name(arg).to_string());
+            }
+        }
+    }
+
+    let mut binaries = vec![];
+    for (program, (tool, program_flags)) in flags {
+        let help = run(&program, tool.help_args()).await?;
+        let unsupported: Vec<&str> = program_flags
+            .iter()
+            .filter(|flag| !supports(&help, flag))
+            .map(|flag| flag.as_str())
+            .collect();
+        if !unsupported.is_empty() {
+            return Err(format!(
+                "{} does not support {}",
+                program,
+                unsupported.join(", ")
+            ));
+        }
+        let version_output = run(&program, tool.version_args()).await?;
+        let version = tool
+            .parse_version(&version_output)
+            .unwrap_or_else(|| "unknown".to_string());
+        binaries.push(BinaryInfo { program, version });
+    }
+    Ok(binaries)
+}
+
+/// Makes sure ffmpeg runs and was built with every one of `filters`, like `libvmaf`, which need a library of their
+/// own. libvmaf's version is listed alongside ffmpeg's when it is one of them, as its scores change between versions.
+pub async fn preflight_ffmpeg(filters: &[String]) -> Result<Vec<BinaryInfo>, String> {
+    let listed = run("ffmpeg", vec!["-hide_banner", "-filters"]).await?;
+    let missing: Vec<&str> = filters
+        .iter()
+        .filter(|filter| !has_filter(&listed, filter))
+        .map(|filter| filter.as_str())
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!("ffmpeg has no {} filter", missing.join(", ")));
+    }
+    let version = run("ffmpeg", vec!["-version"]).await?;
+    let mut binaries = vec![BinaryInfo {
+        program: "ffmpeg".to_string(),
+        version: first_version(&version).unwrap_or_else(|| "unknown".to_string()),
+    }];
+    if filters.iter().any(|filter| filter == "libvmaf") {
+        binaries.push(BinaryInfo {
+            program: "libvmaf".to_string(),
+            version: libvmaf_version().await?,
+        });
+    }
+    Ok(binaries)
+}
+
+/// libvmaf only tells its version in the logs it writes, so score a frame of a test pattern against itself.
+async fn libvmaf_version() -> Result<String, String> {
+    let output = run(
+        "ffmpeg",
+        vec![
+            "-hide_banner",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=size=64x64:duration=0.04",
+            "-f",
+            "lavfi",
+            "-i",
+            "testsrc=size=64x64:duration=0.04",
+            "-lavfi",
+            "[0:v][1:v]libvmaf=log_fmt=json:log_path=/dev/stdout",
+            "-f",
+            "null",
+            "-",
+        ],
+    )
+    .await?;
+    logged_version(&output).ok_or_else(|| format!("libvmaf did not log its version: {}", output))
+}
+
+fn logged_version(output: &str) -> Option<String> {
+    lazy_static! {
+        static ref LOGGED_VERSION_RE: Regex = Regex::new(r#""version":\s*"([^"]+)""#).unwrap();
+    }
+    LOGGED_VERSION_RE
+        .captures(output)
+        .map(|captures| captures[1].to_string())
+}
+
+/// The filters used by an ffmpeg filter graph, named right after the pads going into them.
+pub fn filter_names(graph: &str) -> Vec<String> {
+    lazy_static! {
+        static ref FILTER_RE: Regex = Regex::new(r"\]\s*(\w+)").unwrap();
+    }
+    FILTER_RE
+        .captures_iter(graph)
+        .map(|captures| captures[1].to_string())
+        .collect()
+}
+
+/// `ffmpeg -filters` lists a filter per line as its flags, its name and its pads.
+fn has_filter(listed: &str, filter: &str) -> bool {
+    listed
+        .lines()
+        .any(|line| line.split_whitespace().nth(1) == Some(filter))
+}
+
+async fn run(program: &str, args: Vec<&str>) -> Result<String, String> {
+    // Plenty of encoders print their help to stderr and exit with an error, so only a failure to start counts.
+    let output = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| format!("Could not run {}: {}", program, e))?;
+    let mut text = String::from_utf8_lossy(&output.stdout).to_string();
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    Ok(text)
+}
+
+fn supports(help: &str, flag: &str) -> bool {
+    let listed = |name: &str| {
+        Regex::new(format!(r"(^|[^\w-]){}($|[^\w-])", regex::escape(name)).as_str())
+            .unwrap()
+            .is_match(help)
+    };
+    // Negated flags are commonly documented as --[no-]flag
+    listed(flag)
+        || flag
+            .strip_prefix("--no-")
+            .is_some_and(|rest| listed(format!("--[no-]{}", rest).as_str()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::preflight::{filter_names, has_filter, logged_version, supports};
+
+    #[test]
+    fn finds_flags_in_help() {
+        let help = "Options:\n  -b <arg>, --bit-depth=<arg>  Bit depth\n      --[no-]progress   Show progress\n      --cq-level=<arg>";
+        assert!(supports(help, "-b"));
+        assert!(supports(help, "--bit-depth"));
+        assert!(supports(help, "--cq-level"));
+        assert!(supports(help, "--no-progress"));
+        assert!(!supports(help, "--cq"));
+        assert!(!supports(help, "-o"));
+        assert!(!supports(help, "--enable-keyframe-filtering"));
+    }
+
+    #[test]
+    fn finds_filters() {
+        assert_eq!(
+            filter_names("[distorted][1:v]libvmaf=model='version=vmaf_v0.6.1':n_threads=4"),
+            vec!["libvmaf"]
+        );
+        assert_eq!(filter_names("[distorted][1:v]xpsnr"), vec!["xpsnr"]);
+        let listed = " ... libvmaf          VV->V      Calculate the VMAF between two video streams.\n T.. psnr              VV->V      Calculate the PSNR between two video streams.";
+        assert!(has_filter(listed, "libvmaf"));
+        assert!(has_filter(listed, "psnr"));
+        assert!(!has_filter(listed, "xpsnr"));
+    }
+
+    #[test]
+    fn finds_logged_version() {
+        let output = "[Parsed_libvmaf_0 @ 0x1] VMAF score: 100.000000\n{\n  \"version\": \"3.0.0\",\n  \"fps\": 25.00,";
+        assert_eq!(logged_version(output), Some("3.0.0".to_string()));
+        assert_eq!(logged_version("VMAF score: 100.000000"), None);
+    }
+}
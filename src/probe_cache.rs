@@ -0,0 +1,122 @@
+use serde_json::{Map, Value};
+use std::path::PathBuf;
+use tokio::fs::{read, rename, write};
+use tokio::sync::Mutex;
+
+const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+const FNV_PRIME: u64 = 0x100000001b3;
+
+/// FNV-1a hash of the y4m written for a scene. Unlike the std hashers its output never changes between builds,
+/// which a hash kept on disk needs.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SceneHash(u64);
+
+impl Default for SceneHash {
+    fn default() -> Self {
+        SceneHash(FNV_OFFSET_BASIS)
+    }
+}
+
+impl SceneHash {
+    pub fn update(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn hex(&self) -> String {
+        format!("{:016x}", self.0)
+    }
+}
+
+/// Probe results kept on disk between runs, so a re-run with another target or after a crash can skip the probes
+/// it already did. Entries are keyed by everything that goes into a probe, so a changed scene or encoder setting
+/// just misses the cache. New entries are only kept in memory until the cache is flushed, which happens as each scene
+/// finishes.
+pub struct ProbeCache {
+    path: PathBuf,
+    entries: Mutex<Map<String, Value>>,
+    /// Held while writing the cache out, so two flushes can't write the temporary file at once.
+    flushing: Mutex<()>,
+}
+
+impl ProbeCache {
+    /// Loads the cache at `path`, starting an empty one when there is none or it can't be read.
+    pub async fn load(path: PathBuf) -> ProbeCache {
+        let entries = match read(&path).await {
+            Ok(contents) => match serde_json::from_slice(&contents) {
+                Ok(Value::Object(entries)) => entries,
+                _ => {
+                    println!("Ignoring unreadable probe cache {}", path.display());
+                    Map::new()
+                }
+            },
+            Err(_) => Map::new(),
+        };
+        ProbeCache {
+            path,
+            entries: Mutex::new(entries),
+            flushing: Mutex::new(()),
+        }
+    }
+
+    pub async fn get(&self, key: &str) -> Option<Value> {
+        self.entries.lock().await.get(key).cloned()
+    }
+
+    pub async fn insert(&self, key: String, value: Value) {
+        self.entries.lock().await.insert(key, value);
+    }
+
+    /// Writes the cache out, through a temporary file so a crash can't leave half of it behind.
+    pub async fn flush(&self) {
+        let _flushing = self.flushing.lock().await;
+        let contents = serde_json::to_vec(&*self.entries.lock().await).unwrap();
+        let tmp = self.path.with_extension("tmp");
+        write(&tmp, contents).await.unwrap();
+        rename(&tmp, &self.path).await.unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::probe_cache::{ProbeCache, SceneHash};
+    use serde_json::json;
+
+    #[test]
+    fn scene_hash() {
+        // The FNV-1a test vectors
+        assert_eq!(SceneHash::default().hex(), "cbf29ce484222325");
+        let mut hash = SceneHash::default();
+        hash.update(b"a");
+        assert_eq!(hash.hex(), "af63dc4c8601ec8c");
+        let mut split = SceneHash::default();
+        split.update(b"foo");
+        split.update(b"bar");
+        let mut whole = SceneHash::default();
+        whole.update(b"foobar");
+        assert_eq!(split, whole);
+        assert_eq!(whole.hex(), "85944171f73967e8");
+    }
+
+    #[tokio::test]
+    async fn survives_a_reload() {
+        let path = std::env::temp_dir().join(format!("sav1n-probes-{}.json", std::process::id()));
+        let cache = ProbeCache::load(path.clone()).await;
+        assert_eq!(cache.get("scene").await, None);
+        cache
+            .insert("scene".to_string(), json!({"score": 0.95}))
+            .await;
+        // Nothing is written until the cache is flushed
+        assert!(ProbeCache::load(path.clone())
+            .await
+            .get("scene")
+            .await
+            .is_none());
+        cache.flush().await;
+
+        let reloaded = ProbeCache::load(path.clone()).await;
+        assert_eq!(reloaded.get("scene").await, Some(json!({"score": 0.95})));
+        tokio::fs::remove_file(path).await.unwrap();
+    }
+}
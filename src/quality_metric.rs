@@ -0,0 +1,428 @@
+use crate::plane_metric::PlaneScores;
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::Value;
+use std::fmt::Debug;
+use tokio::process::Command;
+
+/// How per frame scores are combined into the scene's score.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Pooling {
+    Mean,
+    /// Leans towards the worst frames, a handful of bad frames drag it down much further than the mean.
+    Harmonic,
+    /// The score this percentage of frames falls below, so `Percentile(5.0)` targets the worst 5% of frames.
+    Percentile(f64),
+}
+
+impl Pooling {
+    /// Reads `mean`, `harmonic` or a percentile like `p5`.
+    pub fn parse(pooling: &str) -> Option<Pooling> {
+        match pooling {
+            "mean" => Some(Pooling::Mean),
+            "harmonic" => Some(Pooling::Harmonic),
+            _ => pooling
+                .strip_prefix('p')?
+                .parse()
+                .ok()
+                .filter(|p| (0.0..=100.0).contains(p))
+                .map(Pooling::Percentile),
+        }
+    }
+
+    pub fn pool(&self, scores: &[f64]) -> Option<f64> {
+        if scores.is_empty() {
+            return None;
+        }
+        let count = scores.len() as f64;
+        match self {
+            Pooling::Mean => Some(scores.iter().sum::<f64>() / count),
+            // Shifted by one the way libvmaf does it, so a frame scoring 0 doesn't divide by zero
+            Pooling::Harmonic => {
+                Some(count / scores.iter().map(|s| 1.0 / (s + 1.0)).sum::<f64>() - 1.0)
+            }
+            Pooling::Percentile(percentile) => {
+                let mut sorted = scores.to_vec();
+                sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+                let rank = percentile / 100.0 * (count - 1.0);
+                let below = sorted[rank.floor() as usize];
+                let above = sorted[rank.ceil() as usize];
+                Some(below + (above - below) * rank.fract())
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct MetricOptions<'t> {
+    /// The scene's source y4m.
+    pub reference: &'t str,
+    /// Where a metric that logs per frame scores should write them.
+    pub log_file: &'t str,
+    pub height: u32,
+    pub threads: u32,
+}
+
+/// A way of scoring how close an encoded scene is to its source.
+///
+/// The probe decodes the encode with ffmpeg, converting it to the source's pixel format. Metrics ffmpeg knows get
+/// their `filter` run right there, others get the decoded frames as y4m on the stdin of their `scorer`, and metrics
+/// with neither are worked out here from the planes of the decoded frames.
+pub trait QualityMetric: Debug {
+    fn name(&self) -> &str;
+
+    /// Quality target to use when none is given, for metrics whose scale has a well known sweet spot.
+    fn default_target(&self) -> Option<f64> {
+        None
+    }
+
+    /// ffmpeg filter comparing the `[distorted]` encode against the `[1:v]` reference, writing its score to stderr.
+    fn filter(&self, _options: MetricOptions) -> Option<String> {
+        None
+    }
+
+    /// Command reading the decoded encode as y4m from stdin and writing its score to stdout, for metrics with no
+    /// `filter`.
+    fn scorer(&self, _options: MetricOptions) -> Option<Command> {
+        None
+    }
+
+    /// Arguments that make the `scorer` list every flag it supports.
+    fn scorer_help_args(&self) -> Vec<&str> {
+        vec!["--help"]
+    }
+
+    /// Pulls the scene's score out of the filter's or the scorer's output, or the log it wrote, on the metric's usual
+    /// 0-100 scale.
+    fn score(&self, _output: &str, _options: MetricOptions) -> Option<f64> {
+        None
+    }
+
+    /// The scene's score from comparing the planes of its frames, for metrics with neither a `filter` nor a `scorer`.
+    fn score_planes(&self, _scores: &PlaneScores) -> Option<f64> {
+        None
+    }
+}
+
+/// Frames at least this tall are scored with the 4K model, which assumes a viewer sitting closer to the screen.
+const UHD_HEIGHT: u32 = 2160;
+/// Models libvmaf has built in, which it loads by name.
+const BUILT_IN_MODELS: [&str; 4] = [
+    "vmaf_v0.6.1",
+    "vmaf_v0.6.1neg",
+    "vmaf_4k_v0.6.1",
+    "vmaf_b_v0.6.3",
+];
+/// Where the image keeps the files of the models picked by resolution that libvmaf doesn't have built in.
+const MODEL_FOLDER: &str = "/usr/local/share/model";
+
+#[derive(Debug)]
+pub struct Vmaf {
+    pub pooling: Pooling,
+    /// A model file or the name of a model built into libvmaf, picked by resolution when not given.
+    pub model: Option<String>,
+    /// Use the NEG ("no enhancement gain") models when picking by resolution, so sharpening can't inflate the score.
+    pub neg: bool,
+}
+
+unsafe impl Send for Vmaf {}
+
+unsafe impl Sync for Vmaf {}
+
+impl Vmaf {
+    fn model(&self, height: u32) -> String {
+        match &self.model {
+            Some(path) if path.ends_with(".json") || path.contains('/') => format!("path={}", path),
+            Some(name) => format!("version={}", name),
+            None => {
+                let name = format!(
+                    "vmaf_{}v0.6.1{}",
+                    if height >= UHD_HEIGHT { "4k_" } else { "" },
+                    if self.neg { "neg" } else { "" }
+                );
+                if BUILT_IN_MODELS.contains(&name.as_str()) {
+                    format!("version={}", name)
+                } else {
+                    format!("path={}/{}.json", MODEL_FOLDER, name)
+                }
+            }
+        }
+    }
+
+    fn frame_scores(log: &Value) -> Option<Vec<f64>> {
+        log["frames"]
+            .as_array()?
+            .iter()
+            .map(|frame| frame["metrics"]["vmaf"].as_f64())
+            .collect()
+    }
+}
+
+impl QualityMetric for Vmaf {
+    fn name(&self) -> &str {
+        "vmaf"
+    }
+
+    fn default_target(&self) -> Option<f64> {
+        Some(95.0)
+    }
+
+    fn filter(&self, options: MetricOptions) -> Option<String> {
+        Some(format!(
+            "[distorted][1:v]libvmaf=model='{}':n_threads={}:log_fmt=json:log_path={}",
+            self.model(options.height),
+            options.threads,
+            options.log_file
+        ))
+    }
+
+    // The pooled score libvmaf prints is always the mean, so the scene's score comes from its per frame log.
+    fn score(&self, _output: &str, options: MetricOptions) -> Option<f64> {
+        let log: Value =
+            serde_json::from_reader(std::fs::File::open(options.log_file).ok()?).ok()?;
+        let scores = Vmaf::frame_scores(&log)?;
+        self.pooling.pool(&scores)
+    }
+}
+
+/// SSIMULACRA2 through `ssimulacra2_rs`, which picks up ringing and banding that VMAF shrugs off.
+#[derive(Debug)]
+pub struct Ssimulacra2 {}
+
+unsafe impl Send for Ssimulacra2 {}
+
+unsafe impl Sync for Ssimulacra2 {}
+
+impl QualityMetric for Ssimulacra2 {
+    fn name(&self) -> &str {
+        "ssimulacra2"
+    }
+
+    fn scorer(&self, options: MetricOptions) -> Option<Command> {
+        let mut c = Command::new("ssimulacra2_rs");
+        c.arg("video")
+            .arg("-f")
+            .arg(options.threads.to_string())
+            .arg(options.reference)
+            .arg("/dev/stdin");
+        Some(c)
+    }
+
+    fn scorer_help_args(&self) -> Vec<&str> {
+        vec!["video", "--help"]
+    }
+
+    fn score(&self, output: &str, _options: MetricOptions) -> Option<f64> {
+        lazy_static! {
+            static ref MEAN_RE: Regex = Regex::new(r"Mean:\s+(-?[\d.]+)").unwrap();
+        }
+        MEAN_RE.captures(output)?[1].parse().ok()
+    }
+}
+
+/// Identical frames have an infinite PSNR, which would throw the search off.
+const MAX_PSNR: f64 = 100.0;
+
+/// ffmpeg's XPSNR, a PSNR weighted by how visible errors are, scored on luma.
+#[derive(Debug)]
+pub struct Xpsnr {}
+
+unsafe impl Send for Xpsnr {}
+
+unsafe impl Sync for Xpsnr {}
+
+impl QualityMetric for Xpsnr {
+    fn name(&self) -> &str {
+        "xpsnr"
+    }
+
+    fn filter(&self, _options: MetricOptions) -> Option<String> {
+        Some("[distorted][1:v]xpsnr".to_string())
+    }
+
+    fn score(&self, output: &str, _options: MetricOptions) -> Option<f64> {
+        lazy_static! {
+            static ref XPSNR_RE: Regex = Regex::new(r"XPSNR average.*?y:\s*([\d.]+|inf)").unwrap();
+        }
+        XPSNR_RE.captures(output)?[1]
+            .parse()
+            .ok()
+            .map(|psnr: f64| psnr.min(MAX_PSNR))
+    }
+}
+
+/// Plain PSNR averaged over all planes.
+#[derive(Debug)]
+pub struct Psnr {}
+
+unsafe impl Send for Psnr {}
+
+unsafe impl Sync for Psnr {}
+
+impl QualityMetric for Psnr {
+    fn name(&self) -> &str {
+        "psnr"
+    }
+
+    fn filter(&self, _options: MetricOptions) -> Option<String> {
+        Some("[distorted][1:v]psnr".to_string())
+    }
+
+    fn score(&self, output: &str, _options: MetricOptions) -> Option<f64> {
+        lazy_static! {
+            static ref PSNR_RE: Regex = Regex::new(r"PSNR y:.*average:([\d.]+|inf)").unwrap();
+        }
+        PSNR_RE.captures(output)?[1]
+            .parse()
+            .ok()
+            .map(|psnr: f64| psnr.min(MAX_PSNR))
+    }
+}
+
+/// PSNR over all planes worked out here, no ffmpeg filter needed.
+#[derive(Debug)]
+pub struct NativePsnr {}
+
+unsafe impl Send for NativePsnr {}
+
+unsafe impl Sync for NativePsnr {}
+
+impl QualityMetric for NativePsnr {
+    fn name(&self) -> &str {
+        "native_psnr"
+    }
+
+    fn score_planes(&self, scores: &PlaneScores) -> Option<f64> {
+        Some(scores.psnr_all())
+    }
+}
+
+/// SSIM over all planes worked out here, scaled up to 0-100 so targets read like the other metrics.
+#[derive(Debug)]
+pub struct NativeSsim {}
+
+unsafe impl Send for NativeSsim {}
+
+unsafe impl Sync for NativeSsim {}
+
+impl QualityMetric for NativeSsim {
+    fn name(&self) -> &str {
+        "native_ssim"
+    }
+
+    fn score_planes(&self, scores: &PlaneScores) -> Option<f64> {
+        Some(scores.ssim_all() * 100.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::quality_metric::{
+        MetricOptions, Pooling, Psnr, QualityMetric, Ssimulacra2, Vmaf, Xpsnr, BUILT_IN_MODELS,
+    };
+    use serde_json::json;
+
+    const OPTIONS: MetricOptions = MetricOptions {
+        reference: "",
+        log_file: "",
+        height: 1080,
+        threads: 1,
+    };
+
+    #[test]
+    fn parses_scores() {
+        assert_eq!(
+            Ssimulacra2 {}.score(
+                "Video Score for 48 frames\nMean: 78.25\nMedian: 79.00\n",
+                OPTIONS
+            ),
+            Some(78.25)
+        );
+        assert_eq!(
+            Xpsnr {}.score("[Parsed_xpsnr_1 @ 0x5616] XPSNR average, 48 frames  y: 41.2500  u: 44.1000  v: 45.0000  (minimum: 41.2500)\n", OPTIONS),
+            Some(41.25)
+        );
+        assert_eq!(
+            Psnr {}.score(
+                "[Parsed_psnr_1 @ 0x5616] PSNR y:inf u:inf v:inf average:inf min:inf max:inf\n",
+                OPTIONS
+            ),
+            Some(100.0)
+        );
+        assert_eq!(Psnr {}.score("Conversion failed!", OPTIONS), None);
+    }
+
+    #[test]
+    fn reads_vmaf_log() {
+        let log = json!({"frames": [
+            {"frameNum": 0, "metrics": {"vmaf": 98.0}},
+            {"frameNum": 1, "metrics": {"vmaf": 96.0}},
+            {"frameNum": 2, "metrics": {"vmaf": 60.0}}
+        ]});
+        assert_eq!(Vmaf::frame_scores(&log), Some(vec![98.0, 96.0, 60.0]));
+        assert_eq!(Vmaf::frame_scores(&json!({})), None);
+    }
+
+    #[test]
+    fn picks_vmaf_model() {
+        let vmaf = |model: Option<&str>, neg| Vmaf {
+            pooling: Pooling::Mean,
+            model: model.map(|m| m.to_string()),
+            neg,
+        };
+        assert_eq!(vmaf(None, false).model(1080), "version=vmaf_v0.6.1");
+        assert_eq!(vmaf(None, false).model(2160), "version=vmaf_4k_v0.6.1");
+        assert_eq!(vmaf(None, true).model(1080), "version=vmaf_v0.6.1neg");
+        assert_eq!(
+            vmaf(None, true).model(2160),
+            "path=/usr/local/share/model/vmaf_4k_v0.6.1neg.json"
+        );
+        assert_eq!(
+            vmaf(Some("vmaf_b_v0.6.3"), false).model(2160),
+            "version=vmaf_b_v0.6.3"
+        );
+        assert_eq!(
+            vmaf(Some("/models/custom.json"), false).model(1080),
+            "path=/models/custom.json"
+        );
+        // Every model picked by resolution is either built into libvmaf or a file the image ships
+        let dockerfile = include_str!("../Dockerfile");
+        for (height, neg) in [(1080, false), (2160, false), (1080, true), (2160, true)] {
+            let model = vmaf(None, neg).model(height);
+            if let Some(name) = model.strip_prefix("version=") {
+                assert!(BUILT_IN_MODELS.contains(&name), "{}", model);
+            } else {
+                let path = model.strip_prefix("path=").unwrap();
+                let (folder, file) = path.rsplit_once('/').unwrap();
+                assert!(
+                    dockerfile.lines().any(|line| line.starts_with("COPY")
+                        && line.contains(&format!("/model/{} {}/", file, folder))),
+                    "{}",
+                    model
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pooling() {
+        let scores = [98.0, 96.0, 60.0, 94.0, 92.0];
+        assert_eq!(Pooling::Mean.pool(&scores), Some(88.0));
+        assert_eq!(Pooling::Percentile(50.0).pool(&scores), Some(94.0));
+        assert_eq!(Pooling::Percentile(0.0).pool(&scores), Some(60.0));
+        // A fifth of the way from the worst frame to the next
+        assert_eq!(Pooling::Percentile(5.0).pool(&scores), Some(66.4));
+        let harmonic = Pooling::Harmonic.pool(&scores).unwrap();
+        assert!(harmonic < 88.0 && harmonic > 60.0);
+        assert_eq!(Pooling::Mean.pool(&[]), None);
+    }
+
+    #[test]
+    fn parses_pooling() {
+        assert_eq!(Pooling::parse("mean"), Some(Pooling::Mean));
+        assert_eq!(Pooling::parse("harmonic"), Some(Pooling::Harmonic));
+        assert_eq!(Pooling::parse("p5"), Some(Pooling::Percentile(5.0)));
+        assert_eq!(Pooling::parse("p101"), None);
+        assert_eq!(Pooling::parse("median"), None);
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::BTreeMap;
+
+/// How strongly a scene's share of the bits follows its complexity. At 1 bits would be handed out purely by
+/// complexity, which starves quiet scenes, at 0 every second gets the same number of bits.
+const COMPLEXITY_WEIGHT: f64 = 0.6;
+/// How far a scene's budget may stray from its even share of the bitrate, whether from earlier scenes missing their
+/// budget or from the probes coming out a different size than the final encodes.
+const MAX_CORRECTION: f64 = 2.0;
+
+/// Spreads a bitrate over the scenes of a file as they come in. The bits left after the finished scenes are shared
+/// out between the scenes still to finish, each getting its frames weighted by how its first pass complexity compares
+/// to the frames seen so far.
+pub struct RateControl {
+    bits_per_frame: f64,
+    complexity: f64,
+    frames: u64,
+    /// Frames and complexity of the scenes that haven't finished yet.
+    pending: BTreeMap<u32, (u64, f64)>,
+    spent_bits: f64,
+    /// Sizes of final encodes and of the probes at the same value, for scenes that had such a probe.
+    calibration_final_bits: f64,
+    calibration_probe_bits: f64,
+}
+
+impl RateControl {
+    pub fn new(bits_per_second: f64, frame_rate: f64) -> RateControl {
+        RateControl {
+            bits_per_frame: bits_per_second / frame_rate,
+            complexity: 0.0,
+            frames: 0,
+            pending: BTreeMap::new(),
+            spent_bits: 0.0,
+            calibration_final_bits: 0.0,
+            calibration_probe_bits: 0.0,
+        }
+    }
+
+    /// Records the first pass complexity of a scene's frames, which needs to happen before asking for its target.
+    pub fn add_scene(&mut self, scene: u32, frames: u64, complexity: f64) {
+        self.frames += frames;
+        self.complexity += complexity;
+        self.pending.insert(scene, (frames, complexity));
+    }
+
+    /// The scene's frames weighted by its complexity against the mean of every frame seen so far.
+    fn share(&self, frames: u64, complexity: f64) -> f64 {
+        let mean_complexity = self.complexity / self.frames.max(1) as f64;
+        let scene_complexity = complexity / frames.max(1) as f64;
+        let weight = if mean_complexity > 0.0 && scene_complexity > 0.0 {
+            (scene_complexity / mean_complexity).powf(COMPLEXITY_WEIGHT)
+        } else {
+            1.0
+        };
+        frames as f64 * weight
+    }
+
+    /// The number of bits the final encode of a scene should take: its share of the bits the unfinished scenes have
+    /// left between them.
+    pub fn target(&self, scene: u32) -> f64 {
+        let (frames, complexity) = self.pending[&scene];
+        let share = self.share(frames, complexity);
+        let remaining_share: f64 = self
+            .pending
+            .values()
+            .map(|&(frames, complexity)| self.share(frames, complexity))
+            .sum();
+        let remaining_bits = self.bits_per_frame * self.frames as f64 - self.spent_bits;
+        let even = self.bits_per_frame * share;
+        if remaining_share > 0.0 {
+            (remaining_bits * share / remaining_share)
+                .clamp(even / MAX_CORRECTION, even * MAX_CORRECTION)
+        } else {
+            even
+        }
+    }
+
+    /// The scene's `target` in the size of the probes, which run at a different speed than the final encode.
+    pub fn probe_target(&self, scene: u32) -> f64 {
+        self.target(scene) / self.probe_scale()
+    }
+
+    /// Records how many bits a scene ended up taking, along with the size of its probe at the value it was encoded
+    /// at when there was one at the probes' usual speed.
+    pub fn finished(&mut self, scene: u32, bits: f64, probe_bits: Option<f64>) {
+        self.pending.remove(&scene);
+        self.spent_bits += bits;
+        if let Some(probe_bits) = probe_bits {
+            self.calibration_final_bits += bits;
+            self.calibration_probe_bits += probe_bits;
+        }
+    }
+
+    /// How much bigger final encodes come out than the probes at the same value.
+    fn probe_scale(&self) -> f64 {
+        if self.calibration_probe_bits > 0.0 && self.calibration_final_bits > 0.0 {
+            (self.calibration_final_bits / self.calibration_probe_bits)
+                .clamp(1.0 / MAX_CORRECTION, MAX_CORRECTION)
+        } else {
+            1.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rate_control::RateControl;
+
+    #[test]
+    fn complex_scenes_get_more_bits() {
+        let mut rate_control = RateControl::new(2400.0, 24.0);
+        rate_control.add_scene(0, 24, 24.0);
+        rate_control.add_scene(1, 24, 240.0);
+        rate_control.add_scene(2, 24, 132.0);
+        assert!(rate_control.target(1) > 2400.0);
+        assert!(rate_control.target(0) < 2400.0);
+        // Every bit gets handed out, and no more
+        let total: f64 = (0..3).map(|scene| rate_control.target(scene)).sum();
+        assert!((total - 7200.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn overshoot_shrinks_later_scenes() {
+        let mut rate_control = RateControl::new(2400.0, 24.0);
+        rate_control.add_scene(0, 24, 24.0);
+        rate_control.add_scene(1, 24, 24.0);
+        rate_control.add_scene(2, 24, 24.0);
+        rate_control.finished(0, 3000.0, None);
+        assert_eq!(rate_control.target(1), 2100.0);
+        // Undershooting afterwards gives the bits back
+        rate_control.finished(1, 1000.0, None);
+        assert_eq!(rate_control.target(2), 3200.0);
+    }
+
+    #[test]
+    fn corrections_are_bounded() {
+        let mut rate_control = RateControl::new(2400.0, 24.0);
+        rate_control.add_scene(0, 24, 24.0);
+        rate_control.add_scene(1, 24, 24.0);
+        rate_control.finished(0, 10000.0, None);
+        assert_eq!(rate_control.target(1), 1200.0);
+    }
+
+    #[test]
+    fn probes_are_calibrated_to_final_encodes() {
+        let mut rate_control = RateControl::new(2400.0, 24.0);
+        rate_control.add_scene(0, 24, 24.0);
+        rate_control.add_scene(1, 24, 24.0);
+        assert_eq!(rate_control.probe_target(1), 2400.0);
+        // Final encodes coming out 20% bigger than their probes make the probes aim 20% lower
+        rate_control.finished(0, 2400.0, Some(2000.0));
+        assert_eq!(rate_control.target(1), 2400.0);
+        assert_eq!(rate_control.probe_target(1), 2000.0);
+    }
+}
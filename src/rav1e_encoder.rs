@@ -0,0 +1,159 @@
+use crate::color::{ColorInfo, Naming};
+use crate::encoder::{Encoder, EncoderOptions, Passes, QualityDirection, QualityParameter};
+use tokio::process::Command;
+
+/// rav1e speeds indexed by the aomenc style `cpu_used` value.
+const SPEEDS: [u32; 7] = [2, 4, 5, 6, 7, 9, 10];
+
+pub struct Rav1eEncoder {}
+
+unsafe impl Send for Rav1eEncoder {}
+
+unsafe impl Sync for Rav1eEncoder {}
+
+impl Rav1eEncoder {
+    fn speed(cpu_used: u32) -> u32 {
+        SPEEDS[(cpu_used as usize).min(SPEEDS.len() - 1)]
+    }
+
+    fn color_args(color: &ColorInfo) -> Vec<String> {
+        let mut args = vec![];
+        let mut push = |flag: &str, value: String| {
+            args.push(flag.to_string());
+            args.push(value);
+        };
+        if let Some(primaries) = color.primaries_name(Naming::Rav1e) {
+            push("--primaries", primaries.to_string());
+        }
+        if let Some(transfer) = color.transfer_name(Naming::Rav1e) {
+            push("--transfer", transfer.to_string());
+        }
+        if let Some(matrix) = color.matrix_name(Naming::Rav1e) {
+            push("--matrix", matrix.to_string());
+        }
+        if let Some(full_range) = color.full_range {
+            push(
+                "--range",
+                if full_range { "Full" } else { "Limited" }.to_string(),
+            );
+        }
+        if let Some(display) = color.mastering_display {
+            push("--mastering-display", display.decimal());
+        }
+        if let Some(light) = color.content_light {
+            push(
+                "--content-light",
+                format!("{},{}", light.max_cll, light.max_fall),
+            );
+        }
+        args
+    }
+}
+
+impl Encoder for Rav1eEncoder {
+    // rav1e takes the raw 0-255 quantizer index rather than a 0-63 cq-level.
+    // The cq-level scale maps onto it at roughly 4 quantizer steps per level, so these are the aomenc defaults scaled up.
+    fn quality_parameter(&self) -> QualityParameter {
+        QualityParameter {
+            name: "quantizer".to_string(),
+            min: 0,
+            max: 255,
+            direction: QualityDirection::LowerIsBetter,
+            initial_min: 80,
+            initial_max: 160,
+        }
+    }
+
+    fn passes(&self) -> Passes {
+        // rav1e only does two pass encodes when targeting a bitrate, quantizer encodes are single pass.
+        Passes::One
+    }
+
+    // rav1e has no bit depth option, it encodes at the depth of the y4m it is given.
+    fn converts_bit_depth(&self) -> bool {
+        false
+    }
+
+    // There's no lossless switch, quantizer 0 is lossless.
+    fn second_pass(&self, options: EncoderOptions) -> Command {
+        let mut c = Command::new("rav1e");
+        c.arg("--quantizer")
+            .arg(options.cq.min(255).to_string())
+            .arg("--speed")
+            .arg(Rav1eEncoder::speed(options.cpu_used).to_string())
+            .arg("--threads")
+            .arg(options.threads.to_string())
+            // rav1e takes the number of tiles rather than its log2
+            .arg("--tile-cols")
+            .arg((1 << options.tile_columns_log2).to_string())
+            .arg("--tile-rows")
+            .arg((1 << options.tile_rows_log2).to_string())
+            .arg("--keyint")
+            .arg("250")
+            .arg("--quiet")
+            .args(Rav1eEncoder::color_args(options.color))
+            .arg("-y")
+            .arg("-o")
+            .arg(options.output)
+            .arg(options.input);
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::color::ColorInfo;
+    use crate::encoder::{Encoder, EncoderOptions};
+    use crate::rav1e_encoder::Rav1eEncoder;
+
+    #[test]
+    fn speed_mapping() {
+        assert_eq!(Rav1eEncoder::speed(0), 2);
+        assert_eq!(Rav1eEncoder::speed(3), 6);
+        assert_eq!(Rav1eEncoder::speed(6), 10);
+        assert_eq!(Rav1eEncoder::speed(9), 10);
+    }
+
+    #[test]
+    fn color_args() {
+        let color = ColorInfo {
+            primaries: Some(9),
+            transfer: Some(16),
+            matrix: Some(9),
+            full_range: Some(false),
+            ..Default::default()
+        };
+        assert_eq!(
+            Rav1eEncoder::color_args(&color),
+            [
+                "--primaries",
+                "BT2020",
+                "--transfer",
+                "SMPTE2084",
+                "--matrix",
+                "BT2020NCL",
+                "--range",
+                "Limited"
+            ]
+        );
+        assert!(Rav1eEncoder::color_args(&ColorInfo::default()).is_empty());
+    }
+
+    #[test]
+    fn second_pass() {
+        let command = Rav1eEncoder {}.second_pass(EncoderOptions {
+            cq: 300,
+            cpu_used: 4,
+            tile_columns_log2: 2,
+            ..Default::default()
+        });
+        let args: Vec<_> = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        // Quantizers past rav1e's range are clamped
+        assert_eq!(args[0..4], ["--quantizer", "255", "--speed", "7"]);
+        assert_eq!(args[6..8], ["--tile-cols", "4"]);
+    }
+}
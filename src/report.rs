@@ -0,0 +1,357 @@
+use serde_json::{json, Value};
+use std::path::Path;
+use std::process::{ExitStatus, Output};
+use std::time::Duration;
+use tokio::fs::write;
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+use tokio::join;
+use tokio::process::Child;
+use tokio::task;
+
+/// A single probe of a scene's search.
+#[derive(Clone, Debug)]
+pub struct ProbeRecord {
+    pub cq: u32,
+    pub cpu_used: u32,
+    /// The metric's score, or the bits of the encode when searching for a bitrate.
+    pub score: f64,
+    pub chroma: Option<f64>,
+    /// Encoded and scored on clips out of the scene rather than all of it.
+    pub sampled: bool,
+    /// Taken from the probe cache rather than encoded.
+    pub cached: bool,
+    pub wall: Duration,
+    pub cpu: Duration,
+}
+
+/// A score of the scene's final encode, taken when verifying it.
+#[derive(Clone, Debug)]
+pub struct Verification {
+    pub cq: u32,
+    pub score: f64,
+    pub chroma: Option<f64>,
+}
+
+/// Time spent on part of a scene's encode, with CPU time counting the encoder and scoring processes.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct PhaseTime {
+    pub wall: Duration,
+    pub cpu: Duration,
+}
+
+impl PhaseTime {
+    fn to_json(self) -> Value {
+        json!({"wall": self.wall.as_secs_f64(), "cpu": self.cpu.as_secs_f64()})
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SceneReport {
+    pub scene: u32,
+    pub start_frame: u64,
+    pub frames: u64,
+    pub probes: Vec<ProbeRecord>,
+    pub cq: u32,
+    /// No probe of the search that picked `cq` met the target, so it's the end of the range furthest past it.
+    pub missed_target: bool,
+    /// Scores of the final encode and of any re-encodes it took to meet the target.
+    pub verified: Vec<Verification>,
+    /// Size of the final encode in bytes.
+    pub size: u64,
+    /// Bits per second of the final encode.
+    pub bitrate: f64,
+    pub first_pass: PhaseTime,
+    pub search: PhaseTime,
+    pub second_pass: PhaseTime,
+    /// Scoring the final encode, and the searches and re-encodes of scenes that missed the target.
+    pub verify: PhaseTime,
+}
+
+impl SceneReport {
+    /// The last frame of the scene, which still belongs to it.
+    pub fn end_frame(&self) -> u64 {
+        (self.start_frame + self.frames).saturating_sub(1)
+    }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "scene": self.scene,
+            "start_frame": self.start_frame,
+            "end_frame": self.end_frame(),
+            "frames": self.frames,
+            "probes": self.probes.iter().map(|probe| json!({
+                "cq": probe.cq,
+                "cpu_used": probe.cpu_used,
+                "score": probe.score,
+                "chroma": probe.chroma,
+                "sampled": probe.sampled,
+                "cached": probe.cached,
+                "wall": probe.wall.as_secs_f64(),
+                "cpu": probe.cpu.as_secs_f64(),
+            })).collect::<Vec<Value>>(),
+            "cq": self.cq,
+            "missed_target": self.missed_target,
+            "verified": self.verified.iter().map(|verification| json!({
+                "cq": verification.cq,
+                "score": verification.score,
+                "chroma": verification.chroma,
+            })).collect::<Vec<Value>>(),
+            "size": self.size,
+            "bitrate": self.bitrate,
+            "first_pass": self.first_pass.to_json(),
+            "search": self.search.to_json(),
+            "second_pass": self.second_pass.to_json(),
+            "verify": self.verify.to_json(),
+        })
+    }
+}
+
+pub fn to_json(source: &str, scenes: &[SceneReport]) -> Value {
+    json!({
+        "source": source,
+        "scenes": scenes.iter().map(SceneReport::to_json).collect::<Vec<Value>>(),
+    })
+}
+
+/// One row per scene, with its probes and verifications squeezed into a single column each as `cq:score` pairs.
+pub fn to_csv(scenes: &[SceneReport]) -> String {
+    let mut csv = String::from(
+        "scene,start_frame,end_frame,frames,probes,cq,missed_target,verified,size,bitrate,first_pass_wall,first_pass_cpu,\
+         search_wall,search_cpu,second_pass_wall,second_pass_cpu,verify_wall,verify_cpu\n",
+    );
+    for scene in scenes {
+        let probes: Vec<String> = scene
+            .probes
+            .iter()
+            .map(|probe| format!("{}:{:.3}", probe.cq, probe.score))
+            .collect();
+        let verified: Vec<String> = scene
+            .verified
+            .iter()
+            .map(|verification| format!("{}:{:.3}", verification.cq, verification.score))
+            .collect();
+        csv.push_str(&format!(
+            "{},{},{},{},{},{},{},{},{},{:.0},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3},{:.3}\n",
+            scene.scene,
+            scene.start_frame,
+            scene.end_frame(),
+            scene.frames,
+            probes.join(" "),
+            scene.cq,
+            scene.missed_target,
+            verified.join(" "),
+            scene.size,
+            scene.bitrate,
+            scene.first_pass.wall.as_secs_f64(),
+            scene.first_pass.cpu.as_secs_f64(),
+            scene.search.wall.as_secs_f64(),
+            scene.search.cpu.as_secs_f64(),
+            scene.second_pass.wall.as_secs_f64(),
+            scene.second_pass.cpu.as_secs_f64(),
+            scene.verify.wall.as_secs_f64(),
+            scene.verify.cpu.as_secs_f64(),
+        ));
+    }
+    csv
+}
+
+/// Writes the report next to `output` as `.report.json` and `.report.csv`.
+pub async fn write_report(output: &Path, source: &str, scenes: &[SceneReport]) -> io::Result<()> {
+    let json = serde_json::to_vec_pretty(&to_json(source, scenes))?;
+    write(output.with_extension("report.json"), json).await?;
+    write(output.with_extension("report.csv"), to_csv(scenes)).await
+}
+
+/// Waits for `child` to exit, returning the CPU time it used. The CPU time is only there for the report, so it's zero
+/// when it can't be read rather than losing the exit status over it.
+pub async fn wait_with_cpu(child: &mut Child) -> io::Result<(ExitStatus, Duration)> {
+    let cpu = match child.id() {
+        Some(pid) => task::spawn_blocking(move || exited_cpu(pid))
+            .await
+            .ok()
+            .flatten()
+            .unwrap_or(Duration::ZERO),
+        None => Duration::ZERO,
+    };
+    Ok((child.wait().await?, cpu))
+}
+
+/// Waits for `child` to exit, collecting what it wrote to stdout and stderr along with the CPU time it used.
+pub async fn wait_with_output_and_cpu(mut child: Child) -> io::Result<(Output, Duration)> {
+    async fn read_all(pipe: Option<impl AsyncRead + Unpin>) -> io::Result<Vec<u8>> {
+        let mut contents = vec![];
+        if let Some(mut pipe) = pipe {
+            pipe.read_to_end(&mut contents).await?;
+        }
+        Ok(contents)
+    }
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let (stdout, stderr, waited) = join!(
+        read_all(stdout),
+        read_all(stderr),
+        wait_with_cpu(&mut child)
+    );
+    let (status, cpu) = waited?;
+    Ok((
+        Output {
+            status,
+            stdout: stdout?,
+            stderr: stderr?,
+        },
+        cpu,
+    ))
+}
+
+/// Blocks until the process exits and reads the CPU time it used, along with the children it waited for, like wait4
+/// would. The process is left for tokio to reap, `WNOWAIT` keeps its `/proc` entry around until it does.
+fn exited_cpu(pid: u32) -> Option<Duration> {
+    // SAFETY: siginfo_t is plain data that waitid fills in
+    let mut info: libc::siginfo_t = unsafe { std::mem::zeroed() };
+    // SAFETY: info outlives the call
+    while unsafe { libc::waitid(libc::P_PID, pid, &mut info, libc::WEXITED | libc::WNOWAIT) } != 0 {
+        if io::Error::last_os_error().kind() != io::ErrorKind::Interrupted {
+            return None;
+        }
+    }
+    // SAFETY: sysconf has no preconditions
+    let ticks_per_second = u64::try_from(unsafe { libc::sysconf(libc::_SC_CLK_TCK) })
+        .ok()
+        .filter(|&ticks| ticks > 0)?;
+    let stat = std::fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    Some(Duration::from_millis(
+        cpu_ticks(&stat)? * 1000 / ticks_per_second,
+    ))
+}
+
+/// User and system time of a process and of the children it waited for, out of a `/proc/<pid>/stat` line. The
+/// command name can hold spaces and parentheses, so fields are counted from the last `)`.
+fn cpu_ticks(stat: &str) -> Option<u64> {
+    let fields: Vec<&str> = stat[stat.rfind(')')? + 1..]
+        .split_whitespace()
+        .skip(11)
+        .take(4)
+        .collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    fields.iter().map(|field| field.parse::<u64>().ok()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::report::{
+        cpu_ticks, to_csv, to_json, wait_with_cpu, PhaseTime, ProbeRecord, SceneReport,
+        Verification,
+    };
+    use std::time::Duration;
+
+    fn scene() -> SceneReport {
+        SceneReport {
+            scene: 3,
+            start_frame: 240,
+            frames: 48,
+            probes: vec![
+                ProbeRecord {
+                    cq: 20,
+                    cpu_used: 6,
+                    score: 97.25,
+                    chroma: None,
+                    sampled: false,
+                    cached: false,
+                    wall: Duration::from_millis(1500),
+                    cpu: Duration::from_secs(3),
+                },
+                ProbeRecord {
+                    cq: 28,
+                    cpu_used: 3,
+                    score: 95.125,
+                    chroma: Some(44.5),
+                    sampled: true,
+                    cached: true,
+                    wall: Duration::ZERO,
+                    cpu: Duration::ZERO,
+                },
+            ],
+            cq: 27,
+            missed_target: false,
+            verified: vec![
+                Verification {
+                    cq: 28,
+                    score: 93.5,
+                    chroma: None,
+                },
+                Verification {
+                    cq: 27,
+                    score: 94.75,
+                    chroma: None,
+                },
+            ],
+            size: 120_000,
+            bitrate: 480_000.0,
+            first_pass: PhaseTime::default(),
+            search: PhaseTime {
+                wall: Duration::from_millis(1500),
+                cpu: Duration::from_secs(3),
+            },
+            second_pass: PhaseTime {
+                wall: Duration::from_secs(10),
+                cpu: Duration::from_secs(40),
+            },
+            verify: PhaseTime {
+                wall: Duration::from_secs(12),
+                cpu: Duration::from_secs(36),
+            },
+        }
+    }
+
+    #[test]
+    fn json_report() {
+        let report = to_json("test.vpy", &[scene()]);
+        let scene = &report["scenes"][0];
+        assert_eq!(report["source"], "test.vpy");
+        assert_eq!(scene["end_frame"], 287);
+        assert_eq!(scene["probes"][1]["cached"], true);
+        assert_eq!(scene["probes"][1]["chroma"], 44.5);
+        assert_eq!(scene["probes"][1]["sampled"], true);
+        assert_eq!(scene["second_pass"]["cpu"], 40.0);
+        assert_eq!(scene["verified"][0]["score"], 93.5);
+        assert_eq!(scene["cq"], 27);
+        assert_eq!(scene["missed_target"], false);
+    }
+
+    #[test]
+    fn csv_report() {
+        let csv = to_csv(&[scene()]);
+        let mut lines = csv.lines();
+        assert!(lines
+            .next()
+            .unwrap()
+            .starts_with("scene,start_frame,end_frame"));
+        assert_eq!(
+            lines.next().unwrap(),
+            "3,240,287,48,20:97.250 28:95.125,27,false,28:93.500 27:94.750,120000,480000,0.000,0.000,1.500,3.000,\
+             10.000,40.000,12.000,36.000"
+        );
+    }
+
+    #[test]
+    fn reads_cpu_ticks() {
+        let stat =
+            "4242 (aom enc (x)) S 1 4242 4242 0 -1 4194304 1536 0 0 0 250 30 15 5 20 0 1 0 100 0 0";
+        assert_eq!(cpu_ticks(stat), Some(300));
+        assert_eq!(cpu_ticks("garbage"), None);
+    }
+
+    #[tokio::test]
+    async fn counts_cpu_until_exit() {
+        let mut busy = tokio::process::Command::new("sh")
+            .arg("-c")
+            .arg("i=0; while [ $i -lt 200000 ]; do i=$((i+1)); done")
+            .spawn()
+            .unwrap();
+        let (status, cpu) = wait_with_cpu(&mut busy).await.unwrap();
+        assert!(status.success());
+        assert!(cpu > Duration::ZERO);
+    }
+}
@@ -0,0 +1,113 @@
+use crate::frame::{Frame, Status};
+use crate::video_header::VideoHeader;
+use tokio::io::{self, AsyncBufReadExt, AsyncWriteExt};
+
+/// Probes long scenes on a few evenly spaced clips out of them rather than the whole scene.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Sampling {
+    pub clips: u64,
+    pub clip_frames: u64,
+}
+
+impl Sampling {
+    /// Whether a scene is long enough for sampling to at least halve the frames each probe encodes.
+    pub fn applies(&self, frames: u64) -> bool {
+        self.clips > 0 && frames >= self.clips * self.clip_frames * 2
+    }
+
+    /// The first frame of each clip, spread so the first clip starts the scene and the last one ends it.
+    pub fn starts(&self, frames: u64) -> Vec<u64> {
+        let room = frames.saturating_sub(self.clip_frames);
+        if self.clips < 2 {
+            return vec![room / 2];
+        }
+        (0..self.clips)
+            .map(|clip| room * clip / (self.clips - 1))
+            .collect()
+    }
+
+    /// Copies the clips out of a scene's y4m of `frames` frames into a y4m of their own, returning how many frames
+    /// that came to.
+    pub async fn extract(
+        &self,
+        frames: u64,
+        reader: &mut (impl AsyncBufReadExt + Unpin),
+        writer: &mut (impl AsyncWriteExt + Unpin),
+    ) -> io::Result<u64> {
+        let header = VideoHeader::read(reader).await?;
+        let mut frame = Frame::new(header.calc_frame_size(), 0);
+        header.write(writer).await?;
+        let starts = self.starts(frames);
+        let mut written = 0;
+        let mut frame_num = 0;
+        while frame.read(frame_num, reader).await? == Status::Processing {
+            if starts
+                .iter()
+                .any(|start| (*start..start + self.clip_frames).contains(&frame_num))
+            {
+                frame.write(writer).await?;
+                written += 1;
+            }
+            frame_num += 1;
+        }
+        writer.flush().await?;
+        Ok(written)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sampling::Sampling;
+    use std::io::Cursor;
+
+    const SAMPLING: Sampling = Sampling {
+        clips: 3,
+        clip_frames: 10,
+    };
+
+    #[test]
+    fn only_long_scenes() {
+        assert!(!SAMPLING.applies(59));
+        assert!(SAMPLING.applies(60));
+        assert!(!Sampling {
+            clips: 0,
+            clip_frames: 10
+        }
+        .applies(1000));
+    }
+
+    #[test]
+    fn clips_are_evenly_spaced() {
+        assert_eq!(SAMPLING.starts(100), vec![0, 45, 90]);
+        assert_eq!(
+            Sampling {
+                clips: 1,
+                clip_frames: 10
+            }
+            .starts(100),
+            vec![45]
+        );
+    }
+
+    #[tokio::test]
+    async fn extracts_the_clips() {
+        let mut scene: Vec<u8> = b"YUV4MPEG2 W2 H2 F25:1 C444\x0A".to_vec();
+        for frame in 0..60u8 {
+            scene.extend(b"FRAME\x0A");
+            scene.extend([frame; 12]);
+        }
+        let mut sample = vec![];
+        let written = SAMPLING
+            .extract(60, &mut Cursor::new(scene), &mut sample)
+            .await
+            .unwrap();
+        assert_eq!(written, 30);
+        let frame_len = 6 + 12;
+        assert!(sample.starts_with(b"YUV4MPEG2 W2 H2 F25:1 C444\x0A"));
+        let frames = &sample[27..];
+        assert_eq!(frames.len(), 30 * frame_len);
+        assert_eq!(frames[6], 0);
+        assert_eq!(frames[10 * frame_len + 6], 25);
+        assert_eq!(frames[29 * frame_len + 6], 59);
+    }
+}
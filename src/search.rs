@@ -0,0 +1,350 @@
+/// How the next value to probe is picked from the probes done so far.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Strategy {
+    /// Follows the line through the last two probes. Quick when quality changes evenly, but it can overshoot.
+    Secant,
+    /// Halves the bracket every probe, slow but never thrown off by an odd scene.
+    Bisection,
+    /// Regula falsi between the two sides of the bracket, with the Illinois fix so one side can't get stuck.
+    Illinois,
+    /// Fits a line through every probe so far, which smooths out probes that came out a little noisy.
+    ModelFit,
+}
+
+impl Strategy {
+    pub fn parse(strategy: &str) -> Option<Strategy> {
+        match strategy {
+            "secant" => Some(Strategy::Secant),
+            "bisection" => Some(Strategy::Bisection),
+            "illinois" => Some(Strategy::Illinois),
+            "model" => Some(Strategy::ModelFit),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SearchOptions {
+    pub strategy: Strategy,
+    /// A probe meeting the target by no more than this is close enough to stop at.
+    pub tolerance: f64,
+    /// Probes to do after the first two before settling for the best one so far.
+    pub max_iterations: u32,
+}
+
+/// A probed value, as its offset from the passing end of the range, and how far past the target it came out.
+#[derive(Copy, Clone, Debug)]
+struct Probe {
+    offset: u32,
+    distance: f64,
+}
+
+/// Searches a range for the value just meeting a target. Probes are given as how far past the target they came out,
+/// positive when they meet it, which has to shrink going from `pass_end` to `fail_end`.
+///
+/// The search keeps a bracket of the furthest probe meeting the target and the nearest one that doesn't. Guesses
+/// landing outside of it are replaced by its midpoint, so every strategy closes in on the answer, and the answer is
+/// always a value that was seen meeting the target, unless nothing in the range does.
+pub struct Search {
+    options: SearchOptions,
+    pass_end: u32,
+    fail_end: u32,
+    probes: Vec<Probe>,
+    passing: Option<Probe>,
+    failing: Option<Probe>,
+    /// Which side of the bracket the last probes replaced, the passing side when true, and how many times in a row.
+    replaced: Option<(bool, u32)>,
+}
+
+impl Search {
+    pub fn new(options: SearchOptions, pass_end: u32, fail_end: u32) -> Search {
+        Search {
+            options,
+            pass_end,
+            fail_end,
+            probes: vec![],
+            passing: None,
+            failing: None,
+            replaced: None,
+        }
+    }
+
+    pub fn add(&mut self, value: u32, distance: f64) {
+        let probe = Probe {
+            offset: self.offset(value),
+            distance,
+        };
+        self.probes.push(probe);
+        let passes = distance >= 0.0;
+        if passes {
+            if self.passing.is_none_or(|p| probe.offset > p.offset) {
+                self.passing = Some(probe);
+            }
+            // A noisy probe can pass further along than one that failed, believe the newer one
+            if self.failing.is_some_and(|f| f.offset <= probe.offset) {
+                self.failing = None;
+            }
+        } else {
+            if self.failing.is_none_or(|f| probe.offset < f.offset) {
+                self.failing = Some(probe);
+            }
+            if self.passing.is_some_and(|p| p.offset >= probe.offset) {
+                self.passing = None;
+            }
+        }
+        self.replaced = match self.replaced {
+            Some((side, times)) if side == passes => Some((side, times + 1)),
+            _ => Some((passes, 1)),
+        };
+    }
+
+    /// The value to probe next, or `None` once the search is done.
+    pub fn next(&self) -> Option<u32> {
+        if self
+            .passing
+            .is_some_and(|p| p.distance <= self.options.tolerance)
+        {
+            return None;
+        }
+        // Values still worth probing lie strictly between the two sides of the bracket
+        let low = self.passing.map_or(-1, |p| p.offset as i64);
+        let high = self
+            .failing
+            .map_or(self.span() as i64 + 1, |f| f.offset as i64);
+        if high - low < 2 {
+            return None;
+        }
+        let midpoint = (low + high) / 2;
+        let offset = match self.guess() {
+            Some(guess) if guess.is_finite() => {
+                let guess = guess.round() as i64;
+                if guess > low && guess < high {
+                    guess
+                } else if guess <= low && self.passing.is_none() {
+                    // Nothing passes yet, so there's no knowing the answer isn't all the way at the end
+                    0
+                } else if guess >= high && self.failing.is_none() {
+                    self.span() as i64
+                } else {
+                    midpoint
+                }
+            }
+            _ => midpoint,
+        };
+        Some(self.value(offset as u32))
+    }
+
+    /// The value closest to the failing end that was seen meeting the target, or the passing end when none did.
+    pub fn answer(&self) -> u32 {
+        self.passing.map_or(self.pass_end, |p| self.value(p.offset))
+    }
+
+    /// Whether any probe was seen meeting the target, so the answer isn't just the passing end to be safe.
+    pub fn met_target(&self) -> bool {
+        self.passing.is_some()
+    }
+
+    fn guess(&self) -> Option<f64> {
+        match self.options.strategy {
+            Strategy::Secant => self.secant(),
+            Strategy::Bisection => None,
+            Strategy::Illinois => self.illinois().or_else(|| self.secant()),
+            Strategy::ModelFit => self.model_fit(),
+        }
+    }
+
+    fn secant(&self) -> Option<f64> {
+        let [.., older, newer] = self.probes.as_slice() else {
+            return None;
+        };
+        root(*older, *newer)
+    }
+
+    fn illinois(&self) -> Option<f64> {
+        let (mut passing, mut failing) = (self.passing?, self.failing?);
+        // Replacing the same side over and over means the other one is holding the guesses back, so shrink it
+        if let Some((side, times)) = self.replaced {
+            let weight = 0.5f64.powi(times as i32 - 1);
+            if side {
+                failing.distance *= weight;
+            } else {
+                passing.distance *= weight;
+            }
+        }
+        root(passing, failing)
+    }
+
+    /// Where a least squares line through every probe crosses the target.
+    fn model_fit(&self) -> Option<f64> {
+        let count = self.probes.len() as f64;
+        if count < 2.0 {
+            return None;
+        }
+        let mean_offset = self.probes.iter().map(|p| p.offset as f64).sum::<f64>() / count;
+        let mean_distance = self.probes.iter().map(|p| p.distance).sum::<f64>() / count;
+        let (covariance, variance) =
+            self.probes
+                .iter()
+                .fold((0.0, 0.0), |(covariance, variance), p| {
+                    let offset = p.offset as f64 - mean_offset;
+                    (
+                        covariance + offset * (p.distance - mean_distance),
+                        variance + offset * offset,
+                    )
+                });
+        let slope = covariance / variance;
+        // Quality has to drop heading to the failing end, a line going the other way is only noise
+        if !slope.is_finite() || slope >= 0.0 {
+            return None;
+        }
+        Some(mean_offset - mean_distance / slope)
+    }
+
+    fn span(&self) -> u32 {
+        self.pass_end.abs_diff(self.fail_end)
+    }
+
+    fn offset(&self, value: u32) -> u32 {
+        value.abs_diff(self.pass_end)
+    }
+
+    fn value(&self, offset: u32) -> u32 {
+        if self.fail_end >= self.pass_end {
+            self.pass_end + offset
+        } else {
+            self.pass_end - offset
+        }
+    }
+}
+
+/// Where the line through two probes crosses the target.
+fn root(a: Probe, b: Probe) -> Option<f64> {
+    if a.distance == b.distance {
+        return None;
+    }
+    let (a_offset, b_offset) = (a.offset as f64, b.offset as f64);
+    Some(a_offset - a.distance * (a_offset - b_offset) / (a.distance - b.distance))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::search::{Search, SearchOptions, Strategy};
+
+    const STRATEGIES: [Strategy; 4] = [
+        Strategy::Secant,
+        Strategy::Bisection,
+        Strategy::Illinois,
+        Strategy::ModelFit,
+    ];
+
+    /// Runs a search over a cq-level like range, where lower values are better, returning the answer and how
+    /// many probes it took.
+    fn run(strategy: Strategy, quality: impl Fn(u32) -> f64, target: f64) -> (u32, u32) {
+        let options = SearchOptions {
+            strategy,
+            tolerance: 0.005,
+            max_iterations: 10,
+        };
+        let mut search = Search::new(options, 0, 63);
+        let mut probes = 0;
+        for value in [20, 40] {
+            search.add(value, quality(value) - target);
+            probes += 1;
+        }
+        for _ in 0..options.max_iterations {
+            match search.next() {
+                Some(value) => {
+                    search.add(value, quality(value) - target);
+                    probes += 1;
+                }
+                None => break,
+            }
+        }
+        (search.answer(), probes)
+    }
+
+    #[test]
+    fn finds_the_last_value_meeting_the_target() {
+        // Steps of 0.01 so only an exact hit is within the tolerance
+        let quality = |cq: u32| 1.0 - cq as f64 * 0.01;
+        for strategy in STRATEGIES {
+            let (answer, _) = run(strategy, quality, 0.705);
+            assert_eq!(answer, 29, "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn curved_quality() {
+        let quality = |cq: u32| 1.0 - (cq as f64 / 63.0).powi(3);
+        for strategy in STRATEGIES {
+            let (answer, _) = run(strategy, quality, 0.9);
+            assert!(quality(answer) >= 0.9, "{:?}", strategy);
+            assert!(quality(answer + 1) < 0.9 + 0.005, "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn stops_within_tolerance() {
+        let quality = |cq: u32| 1.0 - cq as f64 * 0.01;
+        let (answer, probes) = run(Strategy::Secant, quality, 0.6);
+        assert_eq!(answer, 40);
+        assert_eq!(probes, 2);
+    }
+
+    #[test]
+    fn falls_back_to_the_passing_end() {
+        let quality = |cq: u32| 0.5 - cq as f64 * 0.001;
+        for strategy in STRATEGIES {
+            let (answer, _) = run(strategy, quality, 0.95);
+            assert_eq!(answer, 0, "{:?}", strategy);
+        }
+        let mut search = Search::new(
+            SearchOptions {
+                strategy: Strategy::Secant,
+                tolerance: 0.005,
+                max_iterations: 10,
+            },
+            0,
+            63,
+        );
+        search.add(0, -0.45);
+        assert_eq!(search.answer(), 0);
+        assert!(!search.met_target());
+    }
+
+    #[test]
+    fn anything_passes() {
+        let quality = |_: u32| 1.0;
+        for strategy in STRATEGIES {
+            let (answer, _) = run(strategy, quality, 0.95);
+            assert_eq!(answer, 63, "{:?}", strategy);
+        }
+    }
+
+    #[test]
+    fn higher_is_better() {
+        let mut search = Search::new(
+            SearchOptions {
+                strategy: Strategy::Bisection,
+                tolerance: 0.005,
+                max_iterations: 10,
+            },
+            100,
+            0,
+        );
+        search.add(80, 0.1);
+        search.add(40, -0.1);
+        assert_eq!(search.next(), Some(60));
+        search.add(60, 0.05);
+        assert_eq!(search.next(), Some(50));
+        assert_eq!(search.answer(), 60);
+        assert!(search.met_target());
+    }
+
+    #[test]
+    fn parses_strategies() {
+        assert_eq!(Strategy::parse("illinois"), Some(Strategy::Illinois));
+        assert_eq!(Strategy::parse("model"), Some(Strategy::ModelFit));
+        assert_eq!(Strategy::parse("newton"), None);
+    }
+}
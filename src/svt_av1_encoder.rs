@@ -0,0 +1,132 @@
+use crate::color::ColorInfo;
+use crate::encoder::{Encoder, EncoderOptions, Passes, QualityDirection, QualityParameter};
+use tokio::process::Command;
+
+/// SvtAv1EncApp presets indexed by the aomenc style `cpu_used` value.
+/// aomenc's good quality speeds run 0-6 while SVT-AV1 runs 0-13, with the bottom couple of presets being far slower
+/// than anything aomenc offers, so the mapping starts at 2 and stretches out from there.
+const PRESETS: [u32; 7] = [2, 4, 5, 6, 8, 10, 12];
+const MIN_CRF: u32 = 1;
+const MAX_CRF: u32 = 63;
+
+pub struct SvtAv1Encoder {}
+
+unsafe impl Send for SvtAv1Encoder {}
+
+unsafe impl Sync for SvtAv1Encoder {}
+
+impl SvtAv1Encoder {
+    fn preset(cpu_used: u32) -> u32 {
+        PRESETS[(cpu_used as usize).min(PRESETS.len() - 1)]
+    }
+
+    // SvtAv1EncApp doesn't understand "-" for piping, it wants the stream name spelled out.
+    fn stream(path: &str, stream: &str) -> String {
+        if path == "-" {
+            stream.to_string()
+        } else {
+            path.to_string()
+        }
+    }
+
+    fn color_args(color: &ColorInfo) -> Vec<String> {
+        let mut args = vec![];
+        let mut push = |flag: &str, value: String| {
+            args.push(flag.to_string());
+            args.push(value);
+        };
+        if let Some(primaries) = color.primaries {
+            push("--color-primaries", primaries.to_string());
+        }
+        if let Some(transfer) = color.transfer {
+            push("--transfer-characteristics", transfer.to_string());
+        }
+        if let Some(matrix) = color.matrix {
+            push("--matrix-coefficients", matrix.to_string());
+        }
+        if let Some(full_range) = color.full_range {
+            push("--color-range", (full_range as u32).to_string());
+        }
+        if let Some(display) = color.mastering_display {
+            push("--mastering-display", display.decimal());
+        }
+        if let Some(light) = color.content_light {
+            push(
+                "--content-light",
+                format!("{},{}", light.max_cll, light.max_fall),
+            );
+        }
+        args
+    }
+}
+
+impl Encoder for SvtAv1Encoder {
+    fn quality_parameter(&self) -> QualityParameter {
+        QualityParameter {
+            name: "crf".to_string(),
+            min: MIN_CRF,
+            max: MAX_CRF,
+            direction: QualityDirection::LowerIsBetter,
+            initial_min: 20,
+            initial_max: 40,
+        }
+    }
+
+    fn passes(&self) -> Passes {
+        // SVT-AV1 only does multi-pass for VBR, CRF encodes rely on its own lookahead instead.
+        Passes::One
+    }
+
+    // SVT-AV1 can't change bit depth, it always encodes at the depth of its input.
+    fn converts_bit_depth(&self) -> bool {
+        false
+    }
+
+    fn second_pass(&self, options: EncoderOptions) -> Command {
+        let mut c = Command::new("SvtAv1EncApp");
+        c.arg("--crf")
+            .arg(options.cq.clamp(MIN_CRF, MAX_CRF).to_string())
+            .arg("--preset")
+            .arg(SvtAv1Encoder::preset(options.cpu_used).to_string())
+            .arg("--progress")
+            .arg("0")
+            .arg("--input-depth")
+            .arg(options.input_bit_depth.to_string())
+            .arg("--keyint")
+            .arg("250")
+            .arg("--enable-qm")
+            .arg("1")
+            .arg("--lp")
+            .arg(options.threads.to_string())
+            .arg("--tile-columns")
+            .arg(options.tile_columns_log2.to_string())
+            .arg("--tile-rows")
+            .arg(options.tile_rows_log2.to_string())
+            .args(SvtAv1Encoder::color_args(options.color))
+            .args(
+                options
+                    .lossless
+                    .then_some(["--lossless", "1"])
+                    .into_iter()
+                    .flatten(),
+            )
+            .arg("-i")
+            .arg(SvtAv1Encoder::stream(options.input, "stdin"))
+            .arg("-b")
+            .arg(SvtAv1Encoder::stream(options.output, "stdout"));
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::svt_av1_encoder::SvtAv1Encoder;
+
+    #[test]
+    fn preset_mapping() {
+        assert_eq!(SvtAv1Encoder::preset(0), 2);
+        assert_eq!(SvtAv1Encoder::preset(3), 6);
+        assert_eq!(SvtAv1Encoder::preset(6), 12);
+        assert_eq!(SvtAv1Encoder::preset(9), 12);
+    }
+}
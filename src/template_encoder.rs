@@ -0,0 +1,286 @@
+use crate::encoder::{
+    annex_b_mkvmerge_options, Encoder, EncoderOptions, Passes, QualityDirection, QualityParameter,
+};
+use crate::video_header::VideoHeader;
+use serde_json::Value;
+use std::io::{Error, ErrorKind};
+use tokio::io;
+use tokio::process::Command;
+
+/// An encoder whose command lines come from a JSON config file rather than being built in code.
+///
+/// Each pass is a list of arguments, starting with the program, where `{input}`, `{output}`, `{cq}`, `{cpu_used}`,
+/// `{threads}`, `{log_file}`, `{bit_depth}`, `{input_bit_depth}`, `{tile_columns}`, `{tile_rows}` and `{lossless}` are
+/// replaced with the values for the scene being encoded. Tile counts are given as their log2, the way aomenc takes
+/// them, and `{lossless}` is 1 or 0.
+/// ```json
+/// {
+///   "extension": "ivf",
+///   "annex_b": false,
+///   "quality": {"name": "cq-level", "min": 0, "max": 63, "direction": "lower_is_better", "initial_min": 20, "initial_max": 40},
+///   "first_pass": ["aomenc", "--passes=2", "--pass=1", "--fpf={log_file}", "-o", "/dev/null", "{input}"],
+///   "second_pass": ["aomenc", "--passes=2", "--pass=2", "--fpf={log_file}", "--cq-level={cq}", "-o", "{output}", "{input}"]
+/// }
+/// ```
+/// Leaving out `first_pass` makes it a single pass encoder. `help_args` and `version_args` can be set when the
+/// encoder doesn't understand `--help` and `--version`.
+pub struct TemplateEncoder {
+    extension: String,
+    annex_b: bool,
+    quality: QualityParameter,
+    first_pass: Option<Vec<String>>,
+    second_pass: Vec<String>,
+    help_args: Option<Vec<String>>,
+    version_args: Option<Vec<String>>,
+}
+
+unsafe impl Send for TemplateEncoder {}
+
+unsafe impl Sync for TemplateEncoder {}
+
+impl TemplateEncoder {
+    pub fn read(path: &str) -> io::Result<TemplateEncoder> {
+        let file = std::fs::File::open(path)?;
+        let config: Value =
+            serde_json::from_reader(file).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        TemplateEncoder::parse(&config)
+    }
+
+    fn parse(config: &Value) -> io::Result<TemplateEncoder> {
+        let quality = &config["quality"];
+        let direction = match &quality["direction"] {
+            Value::Null => QualityDirection::LowerIsBetter,
+            Value::String(direction) if direction == "lower_is_better" => {
+                QualityDirection::LowerIsBetter
+            }
+            Value::String(direction) if direction == "higher_is_better" => {
+                QualityDirection::HigherIsBetter
+            }
+            other => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Unknown quality direction {}", other),
+                ))
+            }
+        };
+        let second_pass = TemplateEncoder::command_template(&config["second_pass"])?
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Missing second_pass"))?;
+        let quality = QualityParameter {
+            name: quality["name"].as_str().unwrap_or("cq").to_string(),
+            min: TemplateEncoder::number(quality, "min")?,
+            max: TemplateEncoder::number(quality, "max")?,
+            direction,
+            initial_min: TemplateEncoder::number(quality, "initial_min")?,
+            initial_max: TemplateEncoder::number(quality, "initial_max")?,
+        };
+        // The search starts out probing the initial values and never leaves the range
+        if !(quality.min <= quality.initial_min
+            && quality.initial_min <= quality.initial_max
+            && quality.initial_max <= quality.max)
+        {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Quality values have to go min <= initial_min <= initial_max <= max",
+            ));
+        }
+        Ok(TemplateEncoder {
+            extension: config["extension"].as_str().unwrap_or("ivf").to_string(),
+            annex_b: config["annex_b"].as_bool().unwrap_or(false),
+            quality,
+            first_pass: TemplateEncoder::command_template(&config["first_pass"])?,
+            second_pass,
+            help_args: TemplateEncoder::args(&config["help_args"])?,
+            version_args: TemplateEncoder::args(&config["version_args"])?,
+        })
+    }
+
+    fn number(value: &Value, field: &str) -> io::Result<u32> {
+        value[field]
+            .as_u64()
+            .map(|n| n as u32)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Missing quality {}", field)))
+    }
+
+    fn args(value: &Value) -> io::Result<Option<Vec<String>>> {
+        if value.is_null() {
+            return Ok(None);
+        }
+        value
+            .as_array()
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Arguments must be lists"))?
+            .iter()
+            .map(|arg| arg.as_str().map(|a| a.to_string()))
+            .collect::<Option<Vec<String>>>()
+            .map(Some)
+            .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Arguments must be strings"))
+    }
+
+    fn command_template(value: &Value) -> io::Result<Option<Vec<String>>> {
+        let args = TemplateEncoder::args(value)?;
+        if args.as_ref().is_some_and(|a| a.is_empty()) {
+            return Err(Error::new(ErrorKind::InvalidData, "Passes need a program"));
+        }
+        Ok(args)
+    }
+
+    fn fill(template: &[String], options: &EncoderOptions) -> Vec<String> {
+        template
+            .iter()
+            .map(|arg| {
+                arg.replace("{input}", options.input)
+                    .replace("{output}", options.output)
+                    .replace("{cq}", options.cq.to_string().as_str())
+                    .replace("{cpu_used}", options.cpu_used.to_string().as_str())
+                    .replace("{threads}", options.threads.to_string().as_str())
+                    .replace("{log_file}", options.log_file)
+                    .replace("{bit_depth}", options.bit_depth.to_string().as_str())
+                    .replace(
+                        "{input_bit_depth}",
+                        options.input_bit_depth.to_string().as_str(),
+                    )
+                    .replace(
+                        "{tile_columns}",
+                        options.tile_columns_log2.to_string().as_str(),
+                    )
+                    .replace("{tile_rows}", options.tile_rows_log2.to_string().as_str())
+                    .replace("{lossless}", if options.lossless { "1" } else { "0" })
+            })
+            .collect()
+    }
+
+    fn command(template: &[String], options: &EncoderOptions) -> Command {
+        let args = TemplateEncoder::fill(template, options);
+        let mut c = Command::new(&args[0]);
+        c.args(&args[1..]);
+        c
+    }
+}
+
+impl Encoder for TemplateEncoder {
+    fn quality_parameter(&self) -> QualityParameter {
+        self.quality.clone()
+    }
+
+    fn extension(&self) -> &str {
+        self.extension.as_str()
+    }
+
+    fn mkvmerge_options(&self, header: &VideoHeader) -> Vec<String> {
+        if self.annex_b {
+            annex_b_mkvmerge_options(header)
+        } else {
+            vec![]
+        }
+    }
+
+    fn passes(&self) -> Passes {
+        match self.first_pass {
+            Some(_) => Passes::Two,
+            None => Passes::One,
+        }
+    }
+
+    fn help_args(&self) -> Vec<&str> {
+        match &self.help_args {
+            Some(args) => args.iter().map(|a| a.as_str()).collect(),
+            None => vec!["--help"],
+        }
+    }
+
+    fn version_args(&self) -> Vec<&str> {
+        match &self.version_args {
+            Some(args) => args.iter().map(|a| a.as_str()).collect(),
+            None => vec!["--version"],
+        }
+    }
+
+    fn first_pass(&self, options: EncoderOptions) -> Command {
+        TemplateEncoder::command(self.first_pass.as_ref().unwrap(), &options)
+    }
+
+    fn second_pass(&self, options: EncoderOptions) -> Command {
+        TemplateEncoder::command(&self.second_pass, &options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::{EncoderOptions, QualityDirection};
+    use crate::template_encoder::TemplateEncoder;
+    use serde_json::json;
+
+    #[test]
+    fn parse_and_fill() {
+        let encoder = TemplateEncoder::parse(&json!({
+            "extension": "obu",
+            "quality": {"name": "q", "min": 1, "max": 100, "direction": "higher_is_better", "initial_min": 40, "initial_max": 80},
+            "second_pass": ["enc", "--q={cq}", "--speed={cpu_used}", "--threads={threads}", "-o", "{output}", "{input}"]
+        }))
+        .unwrap();
+
+        assert_eq!(encoder.extension, "obu");
+        assert!(encoder.first_pass.is_none());
+        assert_eq!(encoder.quality.direction, QualityDirection::HigherIsBetter);
+        assert_eq!(encoder.quality.max, 100);
+        let args = TemplateEncoder::fill(
+            &encoder.second_pass,
+            &EncoderOptions {
+                cq: 55,
+                cpu_used: 3,
+                threads: 2,
+                input: "in.y4m",
+                output: "out.obu",
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            args,
+            vec![
+                "enc",
+                "--q=55",
+                "--speed=3",
+                "--threads=2",
+                "-o",
+                "out.obu",
+                "in.y4m"
+            ]
+        );
+    }
+
+    #[test]
+    fn quality_range_checked() {
+        let parse = |quality| {
+            TemplateEncoder::parse(&json!({
+                "quality": quality,
+                "second_pass": ["enc", "{input}"]
+            }))
+        };
+        assert!(parse(json!({"min": 0, "max": 63, "initial_min": 20, "initial_max": 40})).is_ok());
+        assert!(parse(json!({"min": 0, "max": 63, "initial_min": 40, "initial_max": 20})).is_err());
+        assert!(parse(json!({"min": 0, "max": 63, "initial_min": 20, "initial_max": 70})).is_err());
+        assert!(parse(json!({"min": 10, "max": 63, "initial_min": 5, "initial_max": 40})).is_err());
+        assert!(parse(
+            json!({"min": 0, "max": 63, "initial_min": 20, "initial_max": 40, "direction": "up"})
+        )
+        .is_err());
+        assert!(parse(
+            json!({"min": 0, "max": 63, "initial_min": 20, "initial_max": 40, "direction": 1})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn shipped_configs() {
+        for config in ["encoders/aomenc.json", "encoders/vpxenc.json"] {
+            assert!(TemplateEncoder::read(config).is_ok(), "{}", config);
+        }
+    }
+
+    #[test]
+    fn second_pass_required() {
+        let encoder = TemplateEncoder::parse(&json!({
+            "quality": {"min": 1, "max": 100, "initial_min": 40, "initial_max": 80},
+        }));
+        assert!(encoder.is_err());
+    }
+}
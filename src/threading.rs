@@ -0,0 +1,84 @@
+/// Narrowest tile worth splitting off. Below this the loss in prediction across tile edges outweighs the speed up.
+const MIN_TILE_WIDTH: u32 = 960;
+const MIN_TILE_HEIGHT: u32 = 1080;
+/// AV1 and VP9 both top out at 64 tile columns.
+const MAX_TILE_COLUMNS_LOG2: u32 = 6;
+const MAX_TILE_ROWS_LOG2: u32 = 6;
+/// With row based multithreading each tile keeps a handful of threads busy, more than that just sit waiting.
+const THREADS_PER_TILE: u32 = 4;
+
+/// How an encode of a scene should split up its frames and how many threads it gets.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Threading {
+    pub threads: u32,
+    pub tile_columns_log2: u32,
+    pub tile_rows_log2: u32,
+}
+
+impl Threading {
+    /// Splits `cores` between `concurrent_encodes` encoders running at the same time, giving each of them as many
+    /// tiles as the resolution can take without fragmenting small frames.
+    pub fn new(width: u32, height: u32, concurrent_encodes: u32, cores: u32) -> Threading {
+        let tile_columns_log2 = tiles_log2(width, MIN_TILE_WIDTH, MAX_TILE_COLUMNS_LOG2);
+        let tile_rows_log2 = tiles_log2(height, MIN_TILE_HEIGHT, MAX_TILE_ROWS_LOG2);
+        let tiles = 1 << (tile_columns_log2 + tile_rows_log2);
+        let threads = (cores / concurrent_encodes.max(1)).clamp(1, tiles * THREADS_PER_TILE);
+        Threading {
+            threads,
+            tile_columns_log2,
+            tile_rows_log2,
+        }
+    }
+
+    /// Uses the cores the machine says are available.
+    pub fn for_machine(width: u32, height: u32, concurrent_encodes: u32) -> Threading {
+        let cores = std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1);
+        Threading::new(width, height, concurrent_encodes, cores)
+    }
+}
+
+fn tiles_log2(size: u32, min_tile_size: u32, max_log2: u32) -> u32 {
+    let mut log2 = 0;
+    while log2 < max_log2 && size >> (log2 + 1) >= min_tile_size {
+        log2 += 1;
+    }
+    log2
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::threading::Threading;
+
+    #[test]
+    fn small_frames_stay_in_one_tile() {
+        let threading = Threading::new(854, 480, 2, 32);
+        assert_eq!(threading.tile_columns_log2, 0);
+        assert_eq!(threading.tile_rows_log2, 0);
+        // One tile can't keep 16 threads busy
+        assert_eq!(threading.threads, 4);
+    }
+
+    #[test]
+    fn hd_splits_columns() {
+        let threading = Threading::new(1920, 1080, 12, 32);
+        assert_eq!(threading.tile_columns_log2, 1);
+        assert_eq!(threading.tile_rows_log2, 0);
+        assert_eq!(threading.threads, 2);
+    }
+
+    #[test]
+    fn uhd_uses_the_cores() {
+        let threading = Threading::new(3840, 2160, 2, 32);
+        assert_eq!(threading.tile_columns_log2, 2);
+        assert_eq!(threading.tile_rows_log2, 1);
+        assert_eq!(threading.threads, 16);
+    }
+
+    #[test]
+    fn always_at_least_one_thread() {
+        let threading = Threading::new(3840, 2160, 24, 8);
+        assert_eq!(threading.threads, 1);
+    }
+}
@@ -55,6 +55,63 @@ impl VideoHeader {
         }
     }
 
+    /// Bits per sample, with a missing colour space assumed to be 10 bit like `calc_frame_size` does.
+    pub fn bit_depth(&self) -> u32 {
+        match self.color_space_type.unwrap_or(C420p10) {
+            ColorSpaceType::C420p10 => 10,
+            _ => 8,
+        }
+    }
+
+    /// Bytes each sample takes up in a frame, samples over 8 bits are stored as little endian pairs.
+    pub fn bytes_per_sample(&self) -> usize {
+        if self.bit_depth() > 8 {
+            2
+        } else {
+            1
+        }
+    }
+
+    /// Width and height in samples of the Y, U and V planes, which are stored one after the other in a frame.
+    pub fn plane_sizes(&self) -> [(usize, usize); 3] {
+        let (width, height) = (self.width as usize, self.height as usize);
+        let (x_shift, y_shift) = match self.color_space_type.unwrap_or(C420p10) {
+            ColorSpaceType::C410 => (2, 1),
+            ColorSpaceType::C411 => (2, 0),
+            ColorSpaceType::C420 | ColorSpaceType::C420p10 => (1, 1),
+            ColorSpaceType::C422 => (1, 0),
+            ColorSpaceType::C440 => (0, 1),
+            ColorSpaceType::C444 => (0, 0),
+        };
+        // Subsampled planes round up so odd sizes keep their last column and row
+        let chroma = (
+            (width + (1 << x_shift) - 1) >> x_shift,
+            (height + (1 << y_shift) - 1) >> y_shift,
+        );
+        [(width, height), chroma, chroma]
+    }
+
+    /// Frames per second, from the `numerator:denominator` rate.
+    pub fn frame_rate(&self) -> f64 {
+        let mut parts = self.rate.split(':');
+        let numerator: f64 = parts.next().and_then(|n| n.parse().ok()).unwrap_or(0.0);
+        let denominator: f64 = parts.next().and_then(|d| d.parse().ok()).unwrap_or(1.0);
+        numerator / denominator
+    }
+
+    /// The ffmpeg pixel format matching the colour space.
+    pub fn pix_fmt(&self) -> &'static str {
+        match self.color_space_type.unwrap_or(C420p10) {
+            ColorSpaceType::C410 => "yuv410p",
+            ColorSpaceType::C411 => "yuv411p",
+            ColorSpaceType::C420 => "yuv420p",
+            ColorSpaceType::C420p10 => "yuv420p10le",
+            ColorSpaceType::C422 => "yuv422p",
+            ColorSpaceType::C440 => "yuv440p",
+            ColorSpaceType::C444 => "yuv444p",
+        }
+    }
+
     pub async fn write(self, writer: &mut (impl AsyncWriteExt + Unpin)) -> io::Result<()> {
         return writer.write_all(self.as_bytes.as_slice()).await;
     }
@@ -105,7 +162,10 @@ impl VideoHeader {
                     "410" => color_space_type = Some(ColorSpaceType::C410),
                     "411" => color_space_type = Some(ColorSpaceType::C411),
                     "420p10" => color_space_type = Some(ColorSpaceType::C420p10),
-                    "420" => color_space_type = Some(ColorSpaceType::C420),
+                    // 8 bit 4:2:0 only differs in where the chroma samples sit, which ffmpeg spells out
+                    "420" | "420jpeg" | "420mpeg2" | "420paldv" => {
+                        color_space_type = Some(ColorSpaceType::C420)
+                    }
                     "422" => color_space_type = Some(ColorSpaceType::C422),
                     "440" => color_space_type = Some(ColorSpaceType::C440),
                     "444" => color_space_type = Some(ColorSpaceType::C444),
@@ -157,11 +217,25 @@ mod tests {
 
         assert_eq!(384, header.width);
         assert_eq!(288, header.height);
+        assert_eq!(10, header.bit_depth());
+        assert_eq!(25.0, header.frame_rate());
         assert_eq!("25:1", header.rate);
         assert_eq!("p", header.interlace.unwrap());
         assert_eq!(ColorSpaceType::C420p10, header.color_space_type.unwrap());
     }
 
+    #[tokio::test]
+    async fn test_8_bit() {
+        let mut vec: Vec<u8> = Vec::new();
+        vec.extend(b"YUV4MPEG2 W384 H288 F25:1 Ip A0:0 C420\x0A");
+        let header = VideoHeader::read(&mut Cursor::new(vec))
+            .await
+            .expect("should succeed");
+
+        assert_eq!(8, header.bit_depth());
+        assert_eq!("yuv420p", header.pix_fmt());
+    }
+
     #[tokio::test]
     async fn test_write() {
         let header_bytes = b"YUV4MPEG2 W384 H288 F25:1 Ip A0:0 C420p10\x0A";
@@ -185,6 +259,8 @@ mod tests {
             .await
             .expect("should succeed");
 
-        assert_eq!(921600, header.calc_frame_size())
+        assert_eq!(921600, header.calc_frame_size());
+        assert_eq!(2, header.bytes_per_sample());
+        assert_eq!([(640, 480), (320, 240), (320, 240)], header.plane_sizes());
     }
 }
@@ -1,21 +1,74 @@
-use crate::encoder::{Encoder, EncoderOptions};
+use crate::encoder::{
+    included_encoder_version, Encoder, EncoderOptions, Passes, QualityDirection, QualityParameter,
+};
 use tokio::process::Command;
 
 pub struct Vp9Encoder {}
 
+/// vpxenc only signals the matrix, as one of the colour spaces VP9 knows about, and the range.
+fn color_space(matrix: Option<u32>) -> Option<&'static str> {
+    match matrix? {
+        0 => Some("sRGB"),
+        1 => Some("bt709"),
+        5 => Some("bt601"),
+        6 => Some("smpte170"),
+        7 => Some("smpte240"),
+        9 | 10 => Some("bt2020"),
+        _ => None,
+    }
+}
+
 unsafe impl Send for Vp9Encoder {}
 
 unsafe impl Sync for Vp9Encoder {}
 
+impl Vp9Encoder {
+    // High bit depth needs profile 2, while 8 bit stays on profile 0 so it plays everywhere.
+    // vpxenc builds without high bit depth support don't know the depth flags at all, so they are left off for 8 bit.
+    fn depth_args(options: &EncoderOptions) -> Vec<String> {
+        if options.bit_depth <= 8 && options.input_bit_depth <= 8 {
+            return vec!["--profile=0".to_string()];
+        }
+        vec![
+            format!("--profile={}", if options.bit_depth > 8 { 2 } else { 0 }),
+            "-b".to_string(),
+            options.bit_depth.to_string(),
+            format!("--input-bit-depth={}", options.input_bit_depth),
+        ]
+    }
+}
+
 impl Encoder for Vp9Encoder {
+    fn quality_parameter(&self) -> QualityParameter {
+        QualityParameter {
+            name: "cq-level".to_string(),
+            min: 0,
+            max: 63,
+            direction: QualityDirection::LowerIsBetter,
+            initial_min: 20,
+            initial_max: 40,
+        }
+    }
+
+    fn passes(&self) -> Passes {
+        Passes::Two
+    }
+
+    // There is no --version, the version is only listed at the bottom of the help
+    fn version_args(&self) -> Vec<&str> {
+        vec!["--help"]
+    }
+
+    fn parse_version(&self, output: &str) -> Option<String> {
+        included_encoder_version(output)
+    }
+
     fn first_pass(&self, options: EncoderOptions) -> Command {
         let mut c = Command::new("vpxenc");
         c.arg("--quiet")
             .arg("--passes=2")
             .arg("--pass=1")
-            .arg("-b")
-            .arg("10")
-            .arg("--profile=2")
+            .args(Vp9Encoder::depth_args(&options))
             .arg(format!("--threads={}", options.threads))
             .arg(format!("--fpf={}", options.log_file))
             .arg("--end-usage=q")
@@ -33,7 +86,7 @@ impl Encoder for Vp9Encoder {
             .arg("--quiet")
             .arg("--passes=2")
             .arg("--pass=2")
-            .arg("--profile=2")
+            .args(Vp9Encoder::depth_args(&options))
             .arg("--good")
             .arg("--lag-in-frames=25")
             .arg("--kf-max-dist=250")
@@ -42,9 +95,18 @@ impl Encoder for Vp9Encoder {
             .arg("--arnr-maxframes=7")
             .arg("--enable-tpl=1")
             .arg(format!("--threads={}", options.threads))
-            .arg("-b")
-            .arg("10")
+            .arg(format!("--tile-columns={}", options.tile_columns_log2))
+            .arg(format!("--tile-rows={}", options.tile_rows_log2))
+            .arg("--row-mt=1")
             .arg("--end-usage=q")
+            .args(color_space(options.color.matrix).map(|c| format!("--color-space={}", c)))
+            .args(
+                options
+                    .color
+                    .full_range
+                    .map(|full| format!("--color-range={}", if full { "full" } else { "studio" })),
+            )
+            .args(options.lossless.then_some("--lossless=1"))
             .arg("--ivf")
             .arg("-o")
             .arg(options.output)
@@ -52,3 +114,30 @@ impl Encoder for Vp9Encoder {
         return c;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::color::ColorInfo;
+    use crate::encoder::{Encoder, EncoderOptions};
+    use crate::vp9_encoder::Vp9Encoder;
+
+    #[test]
+    fn color_args() {
+        let color = ColorInfo {
+            matrix: Some(9),
+            full_range: Some(true),
+            ..Default::default()
+        };
+        let command = Vp9Encoder {}.second_pass(EncoderOptions {
+            color: &color,
+            ..Default::default()
+        });
+        let args: Vec<_> = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        assert!(args.contains(&"--color-space=bt2020".to_string()));
+        assert!(args.contains(&"--color-range=full".to_string()));
+    }
+}
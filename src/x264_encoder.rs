@@ -0,0 +1,113 @@
+use crate::encoder::{
+    annex_b_mkvmerge_options, x26x_color_args, Encoder, EncoderOptions, Passes, QualityDirection,
+    QualityParameter,
+};
+use crate::video_header::VideoHeader;
+use tokio::process::Command;
+
+/// x264 presets indexed by the aomenc style `cpu_used` value.
+const PRESETS: [&str; 7] = [
+    "veryslow", "slower", "slow", "medium", "fast", "faster", "veryfast",
+];
+
+pub struct X264Encoder {}
+
+unsafe impl Send for X264Encoder {}
+
+unsafe impl Sync for X264Encoder {}
+
+impl X264Encoder {
+    fn preset(cpu_used: u32) -> &'static str {
+        PRESETS[(cpu_used as usize).min(PRESETS.len() - 1)]
+    }
+}
+
+impl Encoder for X264Encoder {
+    // x264 uses a 0-51 crf scale
+    fn quality_parameter(&self) -> QualityParameter {
+        QualityParameter {
+            name: "crf".to_string(),
+            min: 0,
+            max: 51,
+            direction: QualityDirection::LowerIsBetter,
+            initial_min: 18,
+            initial_max: 28,
+        }
+    }
+
+    fn extension(&self) -> &str {
+        "h264"
+    }
+
+    fn mkvmerge_options(&self, header: &VideoHeader) -> Vec<String> {
+        annex_b_mkvmerge_options(header)
+    }
+
+    // High10 and 4:4:4 H.264 only play back in software, and x264 is there for the devices that can't do anything
+    // newer, so it sticks to 8 bit 4:2:0 unless told otherwise.
+    fn default_bit_depth(&self, _input_bit_depth: u32) -> u32 {
+        8
+    }
+
+    fn passes(&self) -> Passes {
+        // x264 refuses to combine crf with multiple passes
+        Passes::One
+    }
+
+    // --help only covers the common flags
+    fn help_args(&self) -> Vec<&str> {
+        vec!["--fullhelp"]
+    }
+
+    fn second_pass(&self, options: EncoderOptions) -> Command {
+        let mut c = Command::new("x264");
+        // Only a constant quantizer of 0 is lossless, crf 0 still loses detail at high bit depth
+        if options.lossless {
+            c.arg("--qp").arg("0");
+        } else {
+            c.arg("--crf").arg(options.cq.min(51).to_string());
+        }
+        c.arg("--preset")
+            .arg(X264Encoder::preset(options.cpu_used))
+            .arg("--threads")
+            .arg(options.threads.to_string())
+            .arg("--keyint")
+            .arg("250")
+            .arg("--output-depth")
+            .arg(options.bit_depth.to_string())
+            .arg("--output-csp")
+            .arg("i420")
+            .arg("--quiet")
+            .arg("--no-progress")
+            .args(x26x_color_args(options.color))
+            .arg("--demuxer")
+            .arg("y4m")
+            .arg("-o")
+            .arg(options.output)
+            .arg(options.input);
+        c
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::encoder::{Encoder, EncoderOptions};
+    use crate::x264_encoder::X264Encoder;
+
+    #[test]
+    fn defaults_to_8_bit_420() {
+        let encoder = X264Encoder {};
+        assert_eq!(encoder.default_bit_depth(10), 8);
+        let command = encoder.second_pass(EncoderOptions {
+            bit_depth: encoder.default_bit_depth(10),
+            ..Default::default()
+        });
+        let args: Vec<_> = command
+            .as_std()
+            .get_args()
+            .map(|arg| arg.to_string_lossy().to_string())
+            .collect();
+        let depth = args.iter().position(|arg| arg == "--output-depth").unwrap();
+        assert_eq!(args[depth + 1..depth + 4], ["8", "--output-csp", "i420"]);
+    }
+}
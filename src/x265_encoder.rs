@@ -0,0 +1,105 @@
+use crate::encoder::{
+    annex_b_mkvmerge_options, x26x_color_args, Encoder, EncoderOptions, Passes, QualityDirection,
+    QualityParameter,
+};
+use crate::video_header::VideoHeader;
+use tokio::process::Command;
+
+/// x265 presets indexed by the aomenc style `cpu_used` value.
+const PRESETS: [&str; 7] = [
+    "veryslow", "slower", "slow", "medium", "fast", "faster", "veryfast",
+];
+
+pub struct X265Encoder {}
+
+unsafe impl Send for X265Encoder {}
+
+unsafe impl Sync for X265Encoder {}
+
+impl X265Encoder {
+    fn preset(cpu_used: u32) -> &'static str {
+        PRESETS[(cpu_used as usize).min(PRESETS.len() - 1)]
+    }
+
+    fn hdr_args(options: &EncoderOptions) -> Vec<String> {
+        let mut args = vec![];
+        if let Some(display) = options.color.mastering_display {
+            args.push("--master-display".to_string());
+            args.push(display.sei());
+        }
+        if let Some(light) = options.color.content_light {
+            args.push("--max-cll".to_string());
+            args.push(format!("{},{}", light.max_cll, light.max_fall));
+        }
+        if !args.is_empty() {
+            // HDR10 wants the metadata repeated on every keyframe
+            args.push("--hdr10".to_string());
+            args.push("--repeat-headers".to_string());
+        }
+        args
+    }
+}
+
+impl Encoder for X265Encoder {
+    // x265 uses a 0-51 crf scale
+    fn quality_parameter(&self) -> QualityParameter {
+        QualityParameter {
+            name: "crf".to_string(),
+            min: 0,
+            max: 51,
+            direction: QualityDirection::LowerIsBetter,
+            initial_min: 18,
+            initial_max: 28,
+        }
+    }
+
+    fn extension(&self) -> &str {
+        "hevc"
+    }
+
+    fn mkvmerge_options(&self, header: &VideoHeader) -> Vec<String> {
+        annex_b_mkvmerge_options(header)
+    }
+
+    fn passes(&self) -> Passes {
+        // x265 crf encodes are single pass
+        Passes::One
+    }
+
+    // --help only covers the common flags
+    fn help_args(&self) -> Vec<&str> {
+        vec!["--fullhelp"]
+    }
+
+    fn second_pass(&self, options: EncoderOptions) -> Command {
+        let mut c = Command::new("x265");
+        c.arg("--crf")
+            .arg(options.cq.min(51).to_string())
+            .arg("--preset")
+            .arg(X265Encoder::preset(options.cpu_used))
+            .arg("--pools")
+            .arg(options.threads.to_string())
+            .arg("--keyint")
+            .arg("250")
+            .arg("--output-depth")
+            .arg(options.bit_depth.to_string())
+            .arg("--profile")
+            .arg(if options.bit_depth > 8 {
+                "main10"
+            } else {
+                "main"
+            })
+            .arg("--log-level")
+            .arg("error")
+            .arg("--no-progress")
+            .args(x26x_color_args(options.color))
+            .args(options.lossless.then_some("--lossless"))
+            .args(X265Encoder::hdr_args(&options))
+            .arg("--y4m")
+            .arg("--input")
+            .arg(options.input)
+            .arg("--output")
+            .arg(options.output);
+        c
+    }
+}